@@ -0,0 +1,26 @@
+/* C bindings for the ktls crate, see src/capi.rs. */
+#ifndef KTLS_H
+#define KTLS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+/* All functions return 0 on success and -errno on failure. */
+
+int ktls_setup_ulp(int fd);
+
+/* `version` is e.g. 0x0304 for TLS 1.3, `cipher` one of the kernel's
+ * TLS_CIPHER_* constants from <linux/tls.h>. */
+int ktls_setup_tx(int fd, uint16_t version, uint16_t cipher,
+                  const uint8_t *key, size_t key_len,
+                  const uint8_t *iv, size_t iv_len,
+                  const uint8_t *salt, size_t salt_len,
+                  uint64_t seq);
+
+int ktls_setup_rx(int fd, uint16_t version, uint16_t cipher,
+                  const uint8_t *key, size_t key_len,
+                  const uint8_t *iv, size_t iv_len,
+                  const uint8_t *salt, size_t salt_len,
+                  uint64_t seq);
+
+#endif /* KTLS_H */
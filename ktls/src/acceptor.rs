@@ -0,0 +1,255 @@
+use std::{os::fd::AsFd, sync::Arc, time::Duration};
+
+use rustls::{
+    server::{Acceptor, ClientHello},
+    ServerConfig,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Semaphore,
+};
+use tokio_rustls::{server::TlsStream, LazyConfigAcceptor, TlsAcceptor};
+
+use crate::{
+    config_ktls_server_or_return, AsyncReadReady, CorkStream, Error, KtlsConfig, KtlsStream,
+    OffloadError,
+};
+
+type OffloadPolicy = dyn Fn(Option<&[u8]>) -> bool + Send + Sync;
+
+type ConfigSelector = dyn Fn(&ClientHello<'_>) -> Option<Arc<ServerConfig>> + Send + Sync;
+
+#[derive(Clone)]
+enum ServerConfigSource {
+    Fixed {
+        acceptor: TlsAcceptor,
+        secret_extraction: bool,
+    },
+    PerClientHello(Arc<ConfigSelector>),
+}
+
+/// Wraps a [TlsAcceptor]: does the [CorkStream] wrapping, the handshake and
+/// the offload in a single `accept` call.
+#[derive(Clone)]
+pub struct KtlsAcceptor {
+    inner: ServerConfigSource,
+    offload_policy: Option<Arc<OffloadPolicy>>,
+    handshake_limit: Option<Arc<Semaphore>>,
+    handshake_queue: Option<Arc<Semaphore>>,
+    config: KtlsConfig,
+}
+
+/// What [KtlsAcceptor::accept] returns: either an offloaded stream, or the
+/// rustls stream if the offload policy decided to keep the connection in
+/// userspace, or if it fell back there (see [KtlsConfig::with_fallback]).
+/// The rustls stream is boxed, its state is much larger than a [KtlsStream].
+// The offloaded stream is the common case, it stays inline
+#[allow(clippy::large_enum_variant)]
+pub enum AcceptedStream<IO>
+where
+    IO: AsFd,
+{
+    Ktls(KtlsStream<IO>),
+    Rustls(Box<TlsStream<CorkStream<IO>>>),
+}
+
+impl KtlsAcceptor {
+    pub fn new(config: Arc<ServerConfig>) -> Self {
+        if !config.enable_secret_extraction {
+            tracing::warn!(
+                "enable_secret_extraction isn't set on the ServerConfig, offloads will fail \
+                 (see KtlsServerConfigExt)"
+            );
+        }
+        Self {
+            inner: ServerConfigSource::Fixed {
+                secret_extraction: config.enable_secret_extraction,
+                acceptor: TlsAcceptor::from(config),
+            },
+            offload_policy: None,
+            handshake_limit: None,
+            handshake_queue: None,
+            config: KtlsConfig::default(),
+        }
+    }
+
+    /// Picks the [ServerConfig] for each connection once its ClientHello is
+    /// in (SNI routing, per-tenant certificates...), through a
+    /// [LazyConfigAcceptor]. Returning `None` rejects the connection with
+    /// [Error::ClientHelloRejected]. Every config must have
+    /// `enable_secret_extraction` set.
+    pub fn with_config_selector(
+        selector: impl Fn(&ClientHello<'_>) -> Option<Arc<ServerConfig>> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner: ServerConfigSource::PerClientHello(Arc::new(selector)),
+            offload_policy: None,
+            handshake_limit: None,
+            handshake_queue: None,
+            config: KtlsConfig::default(),
+        }
+    }
+
+    /// Decide whether to offload each connection based on the negotiated ALPN
+    /// protocol (`None` if there was no ALPN). Record size patterns make
+    /// offload a lot more profitable for bulk protocols like `http/1.1` than
+    /// for `h2`, for example. By default, every connection is offloaded.
+    pub fn with_offload_policy(
+        mut self,
+        policy: impl Fn(Option<&[u8]>) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.offload_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Only offload connections that negotiated one of `protocols`, keep the
+    /// others (including those without ALPN) in userspace.
+    pub fn with_offloaded_alpn_protocols<P>(self, protocols: impl IntoIterator<Item = P>) -> Self
+    where
+        P: Into<Vec<u8>>,
+    {
+        let protocols = protocols.into_iter().map(Into::into).collect::<Vec<_>>();
+        self.with_offload_policy(move |alpn| {
+            alpn.is_some_and(|alpn| protocols.iter().any(|p| p == alpn))
+        })
+    }
+
+    /// Fails `accept` with [Error::AcceptTimedOut] if the handshake and the
+    /// offload (including draining the rustls stream) together take longer
+    /// than `timeout`, so a stalling peer can't hold an accept task forever.
+    /// The connection is closed then.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// At most `max` connections go through the handshake and the offload at
+    /// the same time, the others wait for their turn (which counts toward
+    /// [KtlsAcceptor::with_timeout]), see
+    /// [KtlsAcceptor::with_max_queued_handshakes]. Clones of this acceptor
+    /// share the limit.
+    ///
+    /// # Panics
+    ///
+    /// If `max` is 0, no connection would ever be accepted.
+    pub fn with_max_concurrent_handshakes(mut self, max: usize) -> Self {
+        assert!(max > 0, "at least one handshake must be allowed");
+        self.handshake_limit = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// At most `max` connections wait for their turn under
+    /// [KtlsAcceptor::with_max_concurrent_handshakes], `accept` fails with
+    /// [Error::TooManyHandshakes] for the others rather than holding on to
+    /// them. 0 fails every connection past the limit. Clones of this acceptor
+    /// share the queue.
+    pub fn with_max_queued_handshakes(mut self, max: usize) -> Self {
+        self.handshake_queue = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// Offload options, including the timeout (see [KtlsAcceptor::with_timeout])
+    pub fn with_config(mut self, config: KtlsConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub async fn accept<IO>(&self, io: IO) -> Result<AcceptedStream<IO>, Error>
+    where
+        IO: AsFd + AsyncRead + AsyncReadReady + AsyncWrite + Unpin,
+    {
+        let Some(timeout) = self.config.timeout else {
+            return self.accept_inner(io).await;
+        };
+
+        match tokio::time::timeout(timeout, self.accept_inner(io)).await {
+            Ok(res) => res,
+            Err(_) => Err(Error::AcceptTimedOut(timeout)),
+        }
+    }
+
+    async fn accept_inner<IO>(&self, io: IO) -> Result<AcceptedStream<IO>, Error>
+    where
+        IO: AsFd + AsyncRead + AsyncReadReady + AsyncWrite + Unpin,
+    {
+        let _permit = match &self.handshake_limit {
+            Some(limit) => Some(match limit.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    // only held while waiting
+                    let _queued = match &self.handshake_queue {
+                        Some(queue) => {
+                            Some(queue.try_acquire().map_err(|_| Error::TooManyHandshakes)?)
+                        }
+                        None => None,
+                    };
+                    limit.acquire().await.expect("semaphore is never closed")
+                }
+            }),
+            None => None,
+        };
+
+        let (stream, secret_extraction) = match &self.inner {
+            ServerConfigSource::Fixed {
+                acceptor,
+                secret_extraction,
+            } => (
+                acceptor.accept(self.config.cork_stream(io)).await,
+                *secret_extraction,
+            ),
+            ServerConfigSource::PerClientHello(selector) => {
+                let start =
+                    LazyConfigAcceptor::new(Acceptor::default(), self.config.cork_stream(io))
+                        .await
+                        .map_err(Error::HandshakeError)?;
+                let Some(config) = selector(&start.client_hello()) else {
+                    return Err(Error::ClientHelloRejected);
+                };
+                let secret_extraction = config.enable_secret_extraction;
+                (start.into_stream(config).await, secret_extraction)
+            }
+        };
+        let stream = stream.map_err(Error::HandshakeError)?;
+
+        if !self.should_offload(stream.get_ref().1.alpn_protocol()) {
+            return Ok(AcceptedStream::Rustls(Box::new(stream)));
+        }
+
+        // rustls only tells once the connection is consumed
+        let offload = if secret_extraction {
+            config_ktls_server_or_return(stream, &self.config).await
+        } else {
+            Err(OffloadError {
+                error: Error::SecretExtractionDisabled,
+                stream: Some(stream),
+            })
+        };
+
+        match offload {
+            Ok(stream) => Ok(AcceptedStream::Ktls(stream)),
+            Err(OffloadError {
+                error,
+                stream: Some(stream),
+            }) if self.config.fallback => {
+                tracing::debug!(%error, "can't offload, staying in userspace");
+                Ok(AcceptedStream::Rustls(Box::new(stream)))
+            }
+            Err(OffloadError { error, .. }) => Err(error),
+        }
+    }
+
+    fn should_offload(&self, alpn: Option<&[u8]>) -> bool {
+        if !self.config.offload_enabled() {
+            return false;
+        }
+
+        match &self.offload_policy {
+            Some(policy) => {
+                let offload = policy(alpn);
+                tracing::trace!(?alpn, %offload, "applied offload policy");
+                offload
+            }
+            None => true,
+        }
+    }
+}
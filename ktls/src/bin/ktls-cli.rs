@@ -0,0 +1,171 @@
+//! Small diagnostic tool built on the ktls crate: reports what the running
+//! kernel can offload, and compares offloaded vs userspace throughput against
+//! a real server. Build with `cargo build --features cli --bin ktls-cli`.
+
+use std::{
+    error::Error,
+    fs::File,
+    io::BufReader,
+    process::ExitCode,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ktls::{CompatibleCiphers, CompatibleCiphersForVersion, CompatibleDirections, CorkStream};
+use rustls::{ClientConfig, RootCertStore, ServerName};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::TlsConnector;
+
+const USAGE: &str = "usage:
+    ktls-cli probe
+    ktls-cli bench --connect <host:port> --ca <ca.pem> [--server-name <name>] [--bytes <n>]";
+
+const DEFAULT_BENCH_BYTES: u64 = 1024 * 1024 * 1024;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    let res = match args.first().map(String::as_str) {
+        Some("probe") => probe().await,
+        Some("bench") => bench(&args[1..]).await,
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Err(e) = res {
+        eprintln!("error: {e}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+async fn probe() -> Result<()> {
+    let ciphers = CompatibleCiphers::new().await?;
+
+    let print_version =
+        |name, directions: CompatibleDirections, c: &CompatibleCiphersForVersion| {
+            println!(
+                "{name}: tx={} rx={}",
+                yes_no(directions.tx),
+                yes_no(directions.rx)
+            );
+            for (name, directions) in [
+                ("aes_gcm_128", c.aes_gcm_128),
+                ("aes_gcm_256", c.aes_gcm_256),
+                ("chacha20_poly1305", c.chacha20_poly1305),
+            ] {
+                println!(
+                    "  {name:<18} tx={} rx={}",
+                    yes_no(directions.tx),
+                    yes_no(directions.rx)
+                );
+            }
+        };
+    print_version("TLS 1.2", ciphers.versions.tls12, &ciphers.tls12);
+    print_version("TLS 1.3", ciphers.versions.tls13, &ciphers.tls13);
+
+    Ok(())
+}
+
+async fn bench(args: &[String]) -> Result<()> {
+    let mut connect = None;
+    let mut ca = None;
+    let mut server_name = None;
+    let mut bytes = DEFAULT_BENCH_BYTES;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("missing value for {arg}"))
+        };
+        match arg.as_str() {
+            "--connect" => connect = Some(value()?.clone()),
+            "--ca" => ca = Some(value()?.clone()),
+            "--server-name" => server_name = Some(value()?.clone()),
+            "--bytes" => bytes = value()?.parse()?,
+            _ => return Err(format!("unknown argument {arg}\n{USAGE}").into()),
+        }
+    }
+    let connect = connect.ok_or(USAGE)?;
+    let ca = ca.ok_or(USAGE)?;
+    let server_name = server_name.unwrap_or_else(|| {
+        // host part of host:port, brackets stripped for IPv6 literals
+        let host = connect
+            .rsplit_once(':')
+            .map_or(connect.as_str(), |(h, _)| h);
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string()
+    });
+    let server_name = ServerName::try_from(server_name.as_str())?;
+
+    let connector = TlsConnector::from(Arc::new(client_config(&ca)?));
+
+    let tcp = TcpStream::connect(&connect).await?;
+    let mut stream = connector
+        .connect(server_name.clone(), CorkStream::new(tcp))
+        .await?;
+    let userspace = send(&mut stream, bytes).await?;
+    stream.shutdown().await?;
+    report("userspace", bytes, userspace);
+
+    let tcp = TcpStream::connect(&connect).await?;
+    let stream = connector.connect(server_name, CorkStream::new(tcp)).await?;
+    let mut stream = ktls::config_ktls_client(stream).await?;
+    let offloaded = send(&mut stream, bytes).await?;
+    stream.shutdown().await?;
+    report("kTLS", bytes, offloaded);
+
+    Ok(())
+}
+
+fn client_config(ca: &str) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(ca)?)) {
+        roots.add(&rustls::Certificate(cert?.to_vec()))?;
+    }
+
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.enable_secret_extraction = true;
+    Ok(config)
+}
+
+async fn send(stream: &mut (impl AsyncWrite + Unpin), bytes: u64) -> std::io::Result<Duration> {
+    let chunk = vec![0u8; 64 * 1024];
+    let start = Instant::now();
+
+    let mut sent = 0;
+    while sent < bytes {
+        let len = std::cmp::min(chunk.len() as u64, bytes - sent) as usize;
+        stream.write_all(&chunk[..len]).await?;
+        sent += len as u64;
+    }
+    stream.flush().await?;
+
+    Ok(start.elapsed())
+}
+
+fn report(name: &str, bytes: u64, elapsed: Duration) {
+    let mib_per_sec = bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64();
+    println!("{name:>9}: {bytes} bytes in {elapsed:?} ({mib_per_sec:.1} MiB/s)");
+}
+
+fn yes_no(b: bool) -> &'static str {
+    if b {
+        "yes"
+    } else {
+        "no"
+    }
+}
@@ -0,0 +1,106 @@
+//! Sidecar-style bridging between local Unix sockets and kTLS-offloaded TCP
+//! connections, to add TLS in front of (or behind) a legacy local service.
+
+use std::{fmt::Display, future::Future, io, os::fd::AsFd, path::Path, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{AsyncReadReady, Error, KtlsStream};
+
+/// How dialing the other side of a bridge is retried.
+#[derive(Debug, Clone)]
+pub struct Reconnect {
+    /// Number of attempts before giving up on a connection, at least 1
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every failed attempt
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Reconnect {
+    async fn run<T, E, Fut>(&self, mut dial: impl FnMut() -> Fut) -> Result<T, E>
+    where
+        E: Display,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match dial().await {
+                Ok(t) => return Ok(t),
+                Err(e) if attempt >= self.max_attempts => return Err(e),
+                Err(e) => {
+                    tracing::debug!(%e, %attempt, ?backoff, "bridge dial failed, retrying");
+                    tokio::time::sleep(backoff).await;
+                    backoff = std::cmp::min(backoff * 2, self.max_backoff);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Accept local connections on `listener` and forward each of them over a
+/// fresh kTLS connection obtained from `connect` (typically a TCP connect +
+/// handshake + [crate::config_ktls_client]). Failed dials are retried
+/// according to `reconnect`; connections are served concurrently and errors
+/// on one of them are logged without stopping the accept loop.
+pub async fn bridge_unix_to_ktls<IO, F, Fut>(
+    listener: UnixListener,
+    connect: F,
+    reconnect: Reconnect,
+) -> io::Result<()>
+where
+    IO: AsFd + AsyncRead + AsyncReadReady + AsyncWrite + Unpin + Send + 'static,
+    F: Fn() -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<KtlsStream<IO>, Error>> + Send,
+{
+    loop {
+        let (mut local, _addr) = listener.accept().await?;
+        let connect = connect.clone();
+        let reconnect = reconnect.clone();
+
+        tokio::spawn(async move {
+            let mut remote = match reconnect.run(connect).await {
+                Ok(remote) => remote,
+                Err(e) => {
+                    tracing::warn!(%e, "bridge: could not reach the kTLS upstream, dropping");
+                    return;
+                }
+            };
+            if let Err(e) = tokio::io::copy_bidirectional(&mut local, &mut remote).await {
+                tracing::debug!(%e, "bridge: connection closed with an error");
+            }
+        });
+    }
+}
+
+/// Forward an offloaded connection to the local service listening on the Unix
+/// socket at `path`, retrying the dial according to `reconnect`. Returns once
+/// both directions are closed.
+pub async fn bridge_ktls_to_unix<IO>(
+    mut stream: KtlsStream<IO>,
+    path: impl AsRef<Path>,
+    reconnect: Reconnect,
+) -> io::Result<()>
+where
+    IO: AsFd + AsyncRead + AsyncReadReady + AsyncWrite + Unpin,
+{
+    let path = path.as_ref();
+    let mut local = reconnect.run(|| UnixStream::connect(path)).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut local).await?;
+    Ok(())
+}
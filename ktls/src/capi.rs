@@ -0,0 +1,117 @@
+//! C bindings for the kernel plumbing, so that non-Rust servers (nginx
+//! modules, C daemons...) can reuse it once their own TLS library completed
+//! the handshake. Build with:
+//!
+//! ```text
+//! cargo rustc --release --features cdylib --crate-type cdylib
+//! ```
+//!
+//! and see `include/ktls.h` for the matching declarations. All functions
+//! return 0 on success and a negated `errno` value on failure.
+
+use std::os::unix::prelude::RawFd;
+
+use crate::ffi::{self, CryptoInfo, Direction};
+
+/// Enable the `tls` upper level protocol on `fd`. Must be called once,
+/// before `ktls_setup_tx` / `ktls_setup_rx`.
+#[no_mangle]
+pub extern "C" fn ktls_setup_ulp(fd: RawFd) -> libc::c_int {
+    match ffi::setup_ulp(fd) {
+        Ok(()) => 0,
+        Err(e) => -e.raw_os_error().unwrap_or(libc::EIO),
+    }
+}
+
+/// Install the transmit (write) keys on `fd`.
+///
+/// # Safety
+///
+/// `key`, `iv` and `salt` must point to `key_len`, `iv_len` and `salt_len`
+/// readable bytes respectively (`salt` may be NULL if `salt_len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn ktls_setup_tx(
+    fd: RawFd,
+    version: u16,
+    cipher: u16,
+    key: *const u8,
+    key_len: usize,
+    iv: *const u8,
+    iv_len: usize,
+    salt: *const u8,
+    salt_len: usize,
+    seq: u64,
+) -> libc::c_int {
+    setup(
+        fd,
+        Direction::Tx,
+        version,
+        cipher,
+        slice(key, key_len),
+        slice(iv, iv_len),
+        slice(salt, salt_len),
+        seq,
+    )
+}
+
+/// Install the receive (read) keys on `fd`.
+///
+/// # Safety
+///
+/// Same requirements as `ktls_setup_tx`.
+#[no_mangle]
+pub unsafe extern "C" fn ktls_setup_rx(
+    fd: RawFd,
+    version: u16,
+    cipher: u16,
+    key: *const u8,
+    key_len: usize,
+    iv: *const u8,
+    iv_len: usize,
+    salt: *const u8,
+    salt_len: usize,
+    seq: u64,
+) -> libc::c_int {
+    setup(
+        fd,
+        Direction::Rx,
+        version,
+        cipher,
+        slice(key, key_len),
+        slice(iv, iv_len),
+        slice(salt, salt_len),
+        seq,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn setup(
+    fd: RawFd,
+    dir: Direction,
+    version: u16,
+    cipher: u16,
+    key: &[u8],
+    iv: &[u8],
+    salt: &[u8],
+    seq: u64,
+) -> libc::c_int {
+    let info = match CryptoInfo::from_raw(version, cipher, key, iv, salt, seq) {
+        Ok(info) => info,
+        Err(e) => {
+            tracing::debug!(%e, "ktls_setup_{dir:?}: invalid crypto info");
+            return -libc::EINVAL;
+        }
+    };
+
+    match ffi::setup_tls_info(fd, dir, info) {
+        Ok(()) => 0,
+        Err(e) => -e.raw_os_error().unwrap_or(libc::EIO),
+    }
+}
+
+unsafe fn slice<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        return &[];
+    }
+    std::slice::from_raw_parts(ptr, len)
+}
@@ -0,0 +1,188 @@
+use std::{
+    io::{self, IoSlice},
+    ops::{Deref, DerefMut},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    pin::Pin,
+    task,
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{AsyncReadReady, KtlsStream};
+
+/// A [KtlsStream] that sends a `close_notify` when dropped without having
+/// been shut down, see [KtlsStream::close_notify_on_drop].
+///
+/// Without it, a handler that panics or returns early just closes the socket,
+/// which strict peers treat as a truncation attack.
+///
+/// A peer that stops reading can't hold the socket forever: it's closed
+/// without the alert after [CloseNotifyOnDrop::with_timeout].
+pub struct CloseNotifyOnDrop<IO>
+where
+    IO: AsFd + AsyncWrite + Unpin + Send + 'static,
+{
+    // only `None` once dropped or unwrapped
+    stream: Option<KtlsStream<IO>>,
+    timeout: Duration,
+}
+
+/// How long the background task waits for the socket to take the alert by
+/// default
+const CLOSE_NOTIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl<IO> CloseNotifyOnDrop<IO>
+where
+    IO: AsFd + AsyncWrite + Unpin + Send + 'static,
+{
+    pub(crate) fn new(stream: KtlsStream<IO>) -> Self {
+        Self {
+            stream: Some(stream),
+            timeout: CLOSE_NOTIFY_TIMEOUT,
+        }
+    }
+
+    /// How long to wait for the socket to take the `close_notify` (and the
+    /// writes buffered before it) once dropped, 5 seconds by default
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Disarms the guard and returns the stream
+    pub fn into_inner(mut self) -> KtlsStream<IO> {
+        self.stream.take().expect("stream is only taken on drop")
+    }
+
+    fn stream(self: Pin<&mut Self>) -> Pin<&mut KtlsStream<IO>> {
+        Pin::new(self.get_mut().deref_mut())
+    }
+}
+
+impl<IO> Drop for CloseNotifyOnDrop<IO>
+where
+    IO: AsFd + AsyncWrite + Unpin + Send + 'static,
+{
+    fn drop(&mut self) {
+        let Some(mut stream) = self.stream.take() else {
+            return;
+        };
+        if stream.is_write_closed() {
+            return;
+        }
+
+        match tokio::runtime::Handle::try_current() {
+            // the socket might not take the alert (or the writes buffered
+            // before it) right away, so wait until it does
+            Ok(handle) => {
+                let timeout = self.timeout;
+                handle.spawn(async move {
+                    match tokio::time::timeout(timeout, stream.close_notify()).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => {
+                            tracing::debug!(error = %e, "couldn't send close_notify on drop")
+                        }
+                        // the stream goes with the task, closing the socket
+                        Err(_) => {
+                            tracing::debug!(?timeout, "timed out sending close_notify on drop")
+                        }
+                    }
+                });
+            }
+            // best effort, outside a runtime there's nothing to wait on
+            Err(_) => {
+                if let Err(e) = crate::ffi::send_close_notify(stream.as_raw_fd()) {
+                    tracing::debug!(error = %e, "couldn't send close_notify on drop");
+                }
+            }
+        }
+    }
+}
+
+impl<IO> Deref for CloseNotifyOnDrop<IO>
+where
+    IO: AsFd + AsyncWrite + Unpin + Send + 'static,
+{
+    type Target = KtlsStream<IO>;
+
+    fn deref(&self) -> &Self::Target {
+        self.stream.as_ref().expect("stream is only taken on drop")
+    }
+}
+
+impl<IO> DerefMut for CloseNotifyOnDrop<IO>
+where
+    IO: AsFd + AsyncWrite + Unpin + Send + 'static,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.stream.as_mut().expect("stream is only taken on drop")
+    }
+}
+
+impl<IO> AsRawFd for CloseNotifyOnDrop<IO>
+where
+    IO: AsFd + AsyncWrite + Unpin + Send + 'static,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        self.deref().as_raw_fd()
+    }
+}
+
+impl<IO> AsFd for CloseNotifyOnDrop<IO>
+where
+    IO: AsFd + AsyncWrite + Unpin + Send + 'static,
+{
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.deref().as_fd()
+    }
+}
+
+impl<IO> AsyncRead for CloseNotifyOnDrop<IO>
+where
+    IO: AsFd + AsyncRead + AsyncReadReady + AsyncWrite + Unpin + Send + 'static,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        self.stream().poll_read(cx, buf)
+    }
+}
+
+impl<IO> AsyncWrite for CloseNotifyOnDrop<IO>
+where
+    IO: AsFd + AsyncWrite + Unpin + Send + 'static,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> task::Poll<io::Result<usize>> {
+        self.stream().poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> task::Poll<io::Result<usize>> {
+        self.stream().poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.deref().is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<io::Result<()>> {
+        self.stream().poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        self.stream().poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,174 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    CompatibleCiphers, CorkStream, DrainOptions, KeyingMaterialExport, ShutdownMode, TicketCounter,
+};
+
+/// Offload options, for [crate::config_ktls_server_with_config],
+/// [crate::config_ktls_client_with_config], [crate::KtlsAcceptor::with_config]
+/// and [crate::KtlsConnector::with_config]. The defaults are what
+/// `config_ktls_*` do.
+///
+/// Both directions are always offloaded together: once the secrets are
+/// extracted, rustls can't protect records for the other one anymore.
+#[derive(Debug, Clone, Default)]
+pub struct KtlsConfig {
+    pub(crate) drain: DrainOptions,
+    pub(crate) exports: Vec<KeyingMaterialExport>,
+    pub(crate) shutdown_mode: ShutdownMode,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) compatible_ciphers: Option<Arc<CompatibleCiphers>>,
+    pub(crate) offload_disabled: bool,
+    pub(crate) fallback: bool,
+    pub(crate) rekey_policy: RekeyPolicy,
+    pub(crate) session_tickets: Option<SessionTickets>,
+}
+
+/// The session tickets a client waits for before offloading, see
+/// [KtlsConfig::with_session_tickets]
+#[derive(Debug, Clone)]
+pub(crate) struct SessionTickets {
+    pub(crate) counter: Arc<TicketCounter>,
+    pub(crate) count: usize,
+    pub(crate) timeout: Duration,
+}
+
+/// What to do about TLS 1.3 KeyUpdates, which offloaded connections can't
+/// follow: the next traffic secret can't be derived from what rustls gives
+/// out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RekeyPolicy {
+    /// Offload TLS 1.3 connections anyway, reads fail if the peer rekeys
+    #[default]
+    Fail,
+    /// Keep TLS 1.3 connections on rustls (it fails with
+    /// [crate::Error::RekeyUnsupported] where there's no fallback), for
+    /// long-lived connections to peers that rekey
+    Userspace,
+}
+
+impl KtlsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail with [crate::Error::DrainTimedOut] if draining the rustls stream
+    /// takes longer than `timeout` (e.g. because the peer is trickling a
+    /// partial record)
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain.timeout = Some(timeout);
+        self
+    }
+
+    /// Fail with [crate::Error::DrainLimitExceeded] if more than
+    /// `max_drained` bytes of application data came in between the end of
+    /// the handshake and the offload, instead of buffering all of it
+    pub fn with_drain_limit(mut self, max_drained: usize) -> Self {
+        self.drain.max_len = Some(max_drained);
+        self
+    }
+
+    /// Don't drain the rustls stream before offloading: this asserts that the
+    /// peer can't have sent any application data by then, which holds for
+    /// protocols where the server speaks first, on the server side. Whatever
+    /// rustls already decrypted is still picked up, but records it only
+    /// partially read would be lost and break the connection. The acceptor
+    /// and the connector skip [crate::CorkStream] parsing too.
+    pub fn with_skip_drain(mut self, skip: bool) -> Self {
+        self.drain.skip = skip;
+        self
+    }
+
+    /// Keying material to export before the rustls connection is consumed,
+    /// available through [crate::KtlsStream::export_keying_material]
+    pub fn with_exports(mut self, exports: impl IntoIterator<Item = KeyingMaterialExport>) -> Self {
+        self.exports.extend(exports);
+        self
+    }
+
+    /// What shutting the offloaded stream down sends, see
+    /// [crate::KtlsStream::with_shutdown_mode]
+    pub fn with_shutdown_mode(mut self, shutdown_mode: ShutdownMode) -> Self {
+        self.shutdown_mode = shutdown_mode;
+        self
+    }
+
+    /// How long the acceptor and the connector may spend on the handshake and
+    /// the offload together. Not used by `config_ktls_*`, which only drain
+    /// (see [KtlsConfig::with_drain_timeout]).
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Turns offload off for whoever uses this config, like
+    /// [crate::DISABLE_ENV_VAR] and [crate::set_enabled] do for the whole
+    /// process: the acceptor, the connector and `config_ktls_*_or_fallback`
+    /// keep connections on rustls, `config_ktls_*_or_return` give the stream
+    /// back.
+    pub fn with_offload(mut self, enabled: bool) -> Self {
+        self.offload_disabled = !enabled;
+        self
+    }
+
+    /// Whether [crate::KtlsAcceptor] and [crate::KtlsConnector] keep the
+    /// connection on rustls when the offload can't start (see
+    /// [crate::config_ktls_server_or_fallback]) rather than failing.
+    /// `config_ktls_*_or_fallback` always do.
+    pub fn with_fallback(mut self, fallback: bool) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    /// See [RekeyPolicy]
+    pub fn with_rekey_policy(mut self, rekey_policy: RekeyPolicy) -> Self {
+        self.rekey_policy = rekey_policy;
+        self
+    }
+
+    /// On the client side, keeps reading through rustls until `count` more
+    /// TLS 1.3 session tickets reached `counter` (servers typically send 2
+    /// right after the handshake), or `timeout` elapses, then offloads either
+    /// way: tickets received after the offload can't be used for resumption.
+    ///
+    /// `counter` must be the session store of the client config, see
+    /// [TicketCounter]. Tickets are looked up by the SNI the client sent, so
+    /// there's no waiting for servers reached by IP address. Application data
+    /// read meanwhile counts towards [KtlsConfig::with_drain_limit].
+    pub fn with_session_tickets(
+        mut self,
+        counter: Arc<TicketCounter>,
+        count: usize,
+        timeout: Duration,
+    ) -> Self {
+        self.session_tickets = Some(SessionTickets {
+            counter,
+            count,
+            timeout,
+        });
+        self
+    }
+
+    /// Neither this config nor the process-wide switch turned offload off
+    pub(crate) fn offload_enabled(&self) -> bool {
+        !self.offload_disabled && crate::offload_enabled()
+    }
+
+    pub(crate) fn cork_stream<IO>(&self, io: IO) -> CorkStream<IO> {
+        if self.drain.skip {
+            CorkStream::passthrough(io)
+        } else {
+            CorkStream::new(io)
+        }
+    }
+
+    /// What the kernel was probed to support (see [CompatibleCiphers::new]),
+    /// so that `config_ktls_*_or_return` and `config_ktls_*_or_fallback`
+    /// keep connections that negotiated anything else in userspace, instead
+    /// of failing once the secrets are gone. Defaults to the process-wide
+    /// [crate::compatible_ciphers], no check is made if that probe failed.
+    pub fn with_compatible_ciphers(mut self, ciphers: Arc<CompatibleCiphers>) -> Self {
+        self.compatible_ciphers = Some(ciphers);
+        self
+    }
+}
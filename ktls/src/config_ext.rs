@@ -0,0 +1,78 @@
+use rustls::{
+    version::{TLS12, TLS13},
+    ClientConfig, ConfigBuilder, ConfigSide, ServerConfig, WantsCipherSuites, WantsVerifier,
+};
+
+use crate::CompatibleCiphers;
+
+/// Prepares a rustls [ServerConfig] for kTLS. `setup_for_ktls` doesn't take
+/// the [CompatibleCiphers] to prune the cipher suites with: rustls keeps the
+/// suites and protocol versions of a built config private, so they can be
+/// neither checked nor changed. Start from
+/// [CompatibleCiphers::server_config_builder] to only offer what the kernel
+/// supports. None of the public options get in the way of the offload (0-RTT
+/// data and resumption tickets are picked up by `config_ktls_*`), so those
+/// are left alone.
+pub trait KtlsServerConfigExt {
+    /// Turns `enable_secret_extraction` on, without which every
+    /// `config_ktls_*` call fails with [crate::Error::SecretExtractionDisabled]
+    fn setup_for_ktls(&mut self);
+}
+
+impl KtlsServerConfigExt for ServerConfig {
+    fn setup_for_ktls(&mut self) {
+        self.enable_secret_extraction = true;
+    }
+}
+
+/// The client side of [KtlsServerConfigExt], start from
+/// [CompatibleCiphers::client_config_builder] for the cipher suites
+pub trait KtlsClientConfigExt {
+    /// Turns `enable_secret_extraction` on, see
+    /// [KtlsServerConfigExt::setup_for_ktls]
+    fn setup_for_ktls(&mut self);
+}
+
+impl KtlsClientConfigExt for ClientConfig {
+    fn setup_for_ktls(&mut self) {
+        self.enable_secret_extraction = true;
+    }
+}
+
+impl CompatibleCiphers {
+    /// `ServerConfig::builder()`, with the default cipher suites and protocol
+    /// versions restricted to those the kernel can offload, so the handshake
+    /// never settles on one `config_ktls_*` would then fail on. Fails if
+    /// nothing is left.
+    pub fn server_config_builder(
+        &self,
+    ) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, rustls::Error> {
+        self.restrict(ServerConfig::builder())
+    }
+
+    /// The client side of [CompatibleCiphers::server_config_builder]
+    pub fn client_config_builder(
+        &self,
+    ) -> Result<ConfigBuilder<ClientConfig, WantsVerifier>, rustls::Error> {
+        self.restrict(ClientConfig::builder())
+    }
+
+    fn restrict<S: ConfigSide>(
+        &self,
+        builder: ConfigBuilder<S, WantsCipherSuites>,
+    ) -> Result<ConfigBuilder<S, WantsVerifier>, rustls::Error> {
+        let versions = [
+            (&TLS13, rustls::ProtocolVersion::TLSv1_3),
+            (&TLS12, rustls::ProtocolVersion::TLSv1_2),
+        ]
+        .into_iter()
+        .filter(|(_, version)| self.is_version_compatible(*version))
+        .map(|(version, _)| version)
+        .collect::<Vec<_>>();
+
+        builder
+            .with_cipher_suites(&self.filter_suites(rustls::DEFAULT_CIPHER_SUITES))
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&versions)
+    }
+}
@@ -0,0 +1,207 @@
+use rustls::{
+    internal::msgs::{
+        codec::{Codec, Reader},
+        handshake::{
+            ClientExtension, ClientHelloPayload, ClientSessionTicket, HandshakeMessagePayload,
+            HandshakePayload, ServerHelloPayload, ServerNamePayload,
+        },
+    },
+    Certificate, ClientConnection, ProtocolVersion, ServerConnection, SupportedCipherSuite,
+};
+
+use crate::cork_stream::Hellos;
+
+/// What's known about the TLS session that was offloaded. The rustls
+/// connection is consumed by `config_ktls_*`, so this is captured right
+/// before, see [crate::KtlsStream::connection_info].
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionInfo {
+    handshake_kind: HandshakeKind,
+    cipher_suite: Option<SupportedCipherSuite>,
+    protocol_version: Option<ProtocolVersion>,
+    alpn_protocol: Option<Vec<u8>>,
+    peer_certificates: Option<Vec<Certificate>>,
+    server_name: Option<String>,
+    early_data_accepted: bool,
+}
+
+/// Whether the session was established with a full handshake or resumed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HandshakeKind {
+    /// A full handshake
+    Full,
+    /// TLS 1.3 resumption, with a pre-shared key from an earlier session's
+    /// ticket
+    ResumedPsk,
+    /// TLS 1.2 abbreviated handshake, resuming from a ticket (RFC 5077)
+    ResumedTicket,
+    /// TLS 1.2 abbreviated handshake, resuming from a session ID the server
+    /// kept
+    ResumedSessionId,
+    /// TLS 1.2 abbreviated handshake, from a ticket or a session ID: the
+    /// server side can't tell without the ClientHello, which
+    /// `config_ktls_server_parts` doesn't see
+    ResumedSession,
+    /// Clients learn how the session was established from the hellos, which
+    /// `config_ktls_client_parts` doesn't see, nor does a [crate::KtlsStream]
+    /// built by hand
+    #[default]
+    Unknown,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn from_server(conn: &ServerConnection, hellos: &Hellos) -> Self {
+        let client_hello = parse_client_hello(hellos.received.as_deref());
+        let handshake_kind = match (conn.received_resumption_data(), conn.protocol_version()) {
+            (None, _) => HandshakeKind::Full,
+            (Some(_), Some(ProtocolVersion::TLSv1_3)) => HandshakeKind::ResumedPsk,
+            (Some(_), _) => match client_hello {
+                Some(hello) if offers_ticket(&hello) => HandshakeKind::ResumedTicket,
+                Some(_) => HandshakeKind::ResumedSessionId,
+                None => HandshakeKind::ResumedSession,
+            },
+        };
+
+        Self {
+            handshake_kind,
+            server_name: conn.server_name().map(|name| name.to_owned()),
+            cipher_suite: conn.negotiated_cipher_suite(),
+            protocol_version: conn.protocol_version(),
+            alpn_protocol: conn.alpn_protocol().map(|p| p.to_vec()),
+            peer_certificates: conn.peer_certificates().map(|certs| certs.to_vec()),
+            early_data_accepted: false,
+        }
+    }
+
+    pub(crate) fn with_early_data_accepted(mut self, early_data_accepted: bool) -> Self {
+        self.early_data_accepted = early_data_accepted;
+        self
+    }
+
+    pub(crate) fn from_client(conn: &ClientConnection, hellos: &Hellos) -> Self {
+        let client_hello = parse_client_hello(hellos.sent.as_deref());
+        let server_hello = parse_server_hello(hellos.received.as_deref());
+        let handshake_kind = match (conn.protocol_version(), client_hello, server_hello) {
+            (Some(ProtocolVersion::TLSv1_3), _, Some(server_hello)) => {
+                // the server picked one of the PSKs we offered
+                match server_hello.get_psk_index() {
+                    Some(_) => HandshakeKind::ResumedPsk,
+                    None => HandshakeKind::Full,
+                }
+            }
+            (Some(ProtocolVersion::TLSv1_2), Some(client_hello), Some(server_hello)) => {
+                // the server echoes the session ID we offered to resume, which
+                // comes along with a ticket if we had one
+                if server_hello.session_id.is_empty()
+                    || server_hello.session_id != client_hello.session_id
+                {
+                    HandshakeKind::Full
+                } else if offers_ticket(&client_hello) {
+                    HandshakeKind::ResumedTicket
+                } else {
+                    HandshakeKind::ResumedSessionId
+                }
+            }
+            _ => HandshakeKind::Unknown,
+        };
+
+        Self {
+            handshake_kind,
+            server_name: None,
+            cipher_suite: conn.negotiated_cipher_suite(),
+            protocol_version: conn.protocol_version(),
+            alpn_protocol: conn.alpn_protocol().map(|p| p.to_vec()),
+            peer_certificates: conn.peer_certificates().map(|certs| certs.to_vec()),
+            early_data_accepted: conn.is_early_data_accepted(),
+        }
+    }
+
+    /// How the session was established. Resumed sessions don't get a fresh
+    /// certificate check, and TLS 1.3 ones lose forward secrecy for 0-RTT
+    /// data, so this affects the security posture of the connection.
+    pub fn handshake_kind(&self) -> HandshakeKind {
+        self.handshake_kind
+    }
+
+    /// The cipher suite negotiated during the handshake, `None` for a
+    /// [crate::KtlsStream] built by hand
+    pub fn cipher_suite(&self) -> Option<SupportedCipherSuite> {
+        self.cipher_suite
+    }
+
+    /// The TLS version negotiated during the handshake, `None` for a
+    /// [crate::KtlsStream] built by hand
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        self.protocol_version
+    }
+
+    /// The protocol agreed on through ALPN, if any, e.g. `b"h2"`
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.alpn_protocol.as_deref()
+    }
+
+    /// The certificate chain presented by the peer, end-entity first: the
+    /// server's for clients, the client's for servers doing client
+    /// authentication
+    pub fn peer_certificates(&self) -> Option<&[Certificate]> {
+        self.peer_certificates.as_deref()
+    }
+
+    /// The server name the client asked for through SNI. Only known on the
+    /// server side.
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// Whether the server accepted the client's 0-RTT data. On the server
+    /// side, that data is then the first thing read from the stream, and can
+    /// have been replayed by an attacker: only act on it if that's harmless.
+    pub fn early_data_accepted(&self) -> bool {
+        self.early_data_accepted
+    }
+}
+
+/// The first message of a handshake record
+fn parse_handshake(record: Option<&[u8]>) -> Option<HandshakePayload> {
+    HandshakeMessagePayload::read(&mut Reader::init(record?))
+        .ok()
+        .map(|msg| msg.payload)
+}
+
+fn parse_client_hello(record: Option<&[u8]>) -> Option<ClientHelloPayload> {
+    match parse_handshake(record)? {
+        HandshakePayload::ClientHello(hello) => Some(hello),
+        _ => None,
+    }
+}
+
+/// The server name the client sent with SNI, `None` for servers reached by IP
+/// address
+pub(crate) fn sent_server_name(hellos: &Hellos) -> Option<rustls::ServerName> {
+    let hello = parse_client_hello(hellos.sent.as_deref())?;
+    hello
+        .get_sni_extension()?
+        .iter()
+        .find_map(|name| match &name.payload {
+            ServerNamePayload::HostName(dns_name) => dns_name.as_ref().try_into().ok(),
+            _ => None,
+        })
+}
+
+/// `None` for a HelloRetryRequest, after which the ServerHello isn't in the
+/// first record anymore
+fn parse_server_hello(record: Option<&[u8]>) -> Option<ServerHelloPayload> {
+    match parse_handshake(record)? {
+        HandshakePayload::ServerHello(hello) => Some(hello),
+        _ => None,
+    }
+}
+
+/// Whether the ClientHello carries a TLS 1.2 ticket (rather than asking for
+/// one)
+fn offers_ticket(hello: &ClientHelloPayload) -> bool {
+    matches!(
+        hello.get_ticket_extension(),
+        Some(ClientExtension::SessionTicket(ClientSessionTicket::Offer(ticket))) if !ticket.0.is_empty()
+    )
+}
@@ -0,0 +1,211 @@
+use std::{os::fd::AsFd, sync::Arc, time::Duration};
+
+use rustls::{ClientConfig, ServerName};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, ToSocketAddrs},
+};
+use tokio_rustls::TlsConnector;
+
+use crate::{
+    config_ktls_client_or_fallback, config_ktls_client_or_return, socks5, CorkStream, Error,
+    KtlsConfig, MaybeKtlsStream, OffloadError, TicketCounter,
+};
+
+/// Connects to `addr` over TCP, does the handshake and the offload: the whole
+/// pipeline in one call, for tools and tests. The connection stays on rustls
+/// when the offload can't start, see [crate::config_ktls_client_or_fallback].
+pub async fn connect(
+    addr: impl ToSocketAddrs,
+    server_name: ServerName,
+    config: Arc<ClientConfig>,
+) -> Result<MaybeKtlsStream<TcpStream>, Error> {
+    let tcp = TcpStream::connect(addr)
+        .await
+        .map_err(Error::ConnectError)?;
+    let stream = TlsConnector::from(config)
+        .connect(server_name, CorkStream::new(tcp))
+        .await
+        .map_err(Error::HandshakeError)?;
+    config_ktls_client_or_fallback(stream, &KtlsConfig::new()).await
+}
+
+/// Like [connect], but reaches `host:port` through a SOCKS5 proxy (the proxy
+/// resolves `host` if it's a domain name)
+pub async fn connect_via_socks5(
+    proxy: &socks5::Proxy,
+    host: &str,
+    port: u16,
+    server_name: ServerName,
+    config: Arc<ClientConfig>,
+) -> Result<MaybeKtlsStream<TcpStream>, Error> {
+    let tcp = proxy
+        .connect(host, port)
+        .await
+        .map_err(Error::ConnectError)?;
+    let stream = TlsConnector::from(config)
+        .connect(server_name, CorkStream::new(tcp))
+        .await
+        .map_err(Error::HandshakeError)?;
+    config_ktls_client_or_fallback(stream, &KtlsConfig::new()).await
+}
+
+/// Wraps a [TlsConnector]: does the [CorkStream] wrapping, the
+/// handshake and the offload in a single `connect` call, see
+/// [crate::KtlsAcceptor] for the server side.
+#[derive(Clone)]
+pub struct KtlsConnector {
+    inner: TlsConnector,
+    secret_extraction: bool,
+    socks5_proxy: Option<Arc<socks5::Proxy>>,
+    config: KtlsConfig,
+}
+
+impl KtlsConnector {
+    /// `config` must have `enable_secret_extraction` set
+    pub fn new(config: Arc<ClientConfig>) -> Self {
+        if !config.enable_secret_extraction {
+            tracing::warn!(
+                "enable_secret_extraction isn't set on the ClientConfig, offloads will fail \
+                 (see KtlsClientConfigExt)"
+            );
+        }
+        Self {
+            secret_extraction: config.enable_secret_extraction,
+            inner: TlsConnector::from(config),
+            socks5_proxy: None,
+            config: KtlsConfig::default(),
+        }
+    }
+
+    /// Sends 0-RTT data when resuming a session that allows it: what's
+    /// written before the handshake completes goes out with the ClientHello.
+    /// The offload waits for the handshake to complete either way.
+    pub fn with_early_data(mut self, early_data: bool) -> Self {
+        self.inner = self.inner.early_data(early_data);
+        self
+    }
+
+    /// Fails `connect` with [Error::ConnectTimedOut] if the handshake and the
+    /// offload together take longer than `timeout`. The connection is closed
+    /// then.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Waits for `count` TLS 1.3 session tickets before offloading, so the
+    /// next connections can resume, see [KtlsConfig::with_session_tickets]
+    pub fn with_session_tickets(
+        mut self,
+        counter: Arc<TicketCounter>,
+        count: usize,
+        timeout: Duration,
+    ) -> Self {
+        self.config = self.config.with_session_tickets(counter, count, timeout);
+        self
+    }
+
+    /// [KtlsConnector::connect_to] goes through this proxy
+    pub fn with_socks5_proxy(mut self, proxy: socks5::Proxy) -> Self {
+        self.socks5_proxy = Some(Arc::new(proxy));
+        self
+    }
+
+    /// Offload options, including the timeout (see [KtlsConnector::with_timeout])
+    pub fn with_config(mut self, config: KtlsConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The connection stays on rustls if offload is turned off, see
+    /// [crate::set_enabled] and [KtlsConfig::with_offload], or if it can't
+    /// be offloaded and [KtlsConfig::with_fallback] is set
+    pub async fn connect<IO>(
+        &self,
+        domain: ServerName,
+        io: IO,
+    ) -> Result<MaybeKtlsStream<IO>, Error>
+    where
+        IO: AsFd + AsyncRead + AsyncWrite + Unpin,
+    {
+        self.with_timeout_of_config(self.connect_inner(domain, io))
+            .await
+    }
+
+    /// Connects to `host:port` over TCP, through the proxy set with
+    /// [KtlsConnector::with_socks5_proxy] if any, then see
+    /// [KtlsConnector::connect]. The timeout covers the TCP connection too.
+    pub async fn connect_to(
+        &self,
+        domain: ServerName,
+        host: &str,
+        port: u16,
+    ) -> Result<MaybeKtlsStream<TcpStream>, Error> {
+        self.with_timeout_of_config(async {
+            let tcp = match &self.socks5_proxy {
+                Some(proxy) => proxy.connect(host, port).await,
+                None => TcpStream::connect((host, port)).await,
+            }
+            .map_err(Error::ConnectError)?;
+            self.connect_inner(domain, tcp).await
+        })
+        .await
+    }
+
+    async fn with_timeout_of_config<T>(
+        &self,
+        fut: impl std::future::Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let Some(timeout) = self.config.timeout else {
+            return fut.await;
+        };
+
+        match tokio::time::timeout(timeout, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(Error::ConnectTimedOut(timeout)),
+        }
+    }
+
+    async fn connect_inner<IO>(
+        &self,
+        domain: ServerName,
+        io: IO,
+    ) -> Result<MaybeKtlsStream<IO>, Error>
+    where
+        IO: AsFd + AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = self
+            .inner
+            .connect(domain, self.config.cork_stream(io))
+            .await
+            .map_err(Error::HandshakeError)?;
+
+        if !self.config.offload_enabled() {
+            tracing::trace!("offload disabled, staying in userspace");
+            return Ok(MaybeKtlsStream::Rustls(Box::new(stream.into())));
+        }
+
+        // rustls only tells once the connection is consumed
+        let offload = if self.secret_extraction {
+            config_ktls_client_or_return(stream, &self.config).await
+        } else {
+            Err(OffloadError {
+                error: Error::SecretExtractionDisabled,
+                stream: Some(stream),
+            })
+        };
+
+        match offload {
+            Ok(stream) => Ok(MaybeKtlsStream::Ktls(stream)),
+            Err(OffloadError {
+                error,
+                stream: Some(stream),
+            }) if self.config.fallback => {
+                tracing::debug!(%error, "can't offload, staying in userspace");
+                Ok(MaybeKtlsStream::Rustls(Box::new(stream.into())))
+            }
+            Err(OffloadError { error, .. }) => Err(error),
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use std::sync::Arc;
+
+use rustls::AlertDescription;
+
+use crate::AlertLevel;
+
+/// A record other than application data, that the kernel hands back instead
+/// of decrypting it into the read buffer, see
+/// [crate::KtlsStream::with_control_record_handler]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ControlRecord {
+    /// An alert from the peer. Alerts with an unknown level are reported as
+    /// fatal, since that's how they're handled.
+    Alert {
+        level: AlertLevel,
+        description: AlertDescription,
+    },
+    /// Post-handshake messages, e.g. TLS 1.3 `NewSessionTicket` or
+    /// `KeyUpdate`, encoded as on the wire
+    Handshake(Vec<u8>),
+    /// A record of a type kTLS doesn't know about
+    Other { record_type: u8, payload: Vec<u8> },
+}
+
+/// Called with every [ControlRecord] a stream receives, from the task reading
+/// it: it shouldn't block.
+pub type ControlRecordHandler = Arc<dyn Fn(ControlRecord) + Send + Sync>;
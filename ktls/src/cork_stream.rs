@@ -1,4 +1,9 @@
-use std::{io, pin::Pin, task};
+use std::{
+    io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    pin::Pin,
+    task,
+};
 
 use rustls::internal::msgs::codec::Codec;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
@@ -35,6 +40,19 @@ pub struct CorkStream<IO> {
     // if true, causes empty reads at the message boudnary
     pub corked: bool,
     state: State,
+    hellos: Hellos,
+    // set while the first handshake record is being read
+    receiving_hello: bool,
+}
+
+/// The first handshake record each way, which starts with the hello: they
+/// tell how the session was established (see [crate::HandshakeKind]), which
+/// rustls doesn't say on the client side. `sent` is empty when the record
+/// didn't go out in a single write.
+#[derive(Debug, Default)]
+pub(crate) struct Hellos {
+    pub(crate) sent: Option<Vec<u8>>,
+    pub(crate) received: Option<Vec<u8>>,
 }
 
 impl<IO> CorkStream<IO> {
@@ -46,8 +64,31 @@ impl<IO> CorkStream<IO> {
                 header_buf: Default::default(),
                 offset: 0,
             },
+            hellos: Hellos::default(),
+            receiving_hello: false,
+        }
+    }
+
+    /// A `CorkStream` that never parses headers, for connections offloaded
+    /// without draining (see [crate::KtlsConfig::with_skip_drain]): it can't
+    /// be corked.
+    pub fn passthrough(io: IO) -> Self {
+        Self {
+            io,
+            corked: false,
+            state: State::Passthrough,
+            hellos: Hellos::default(),
+            receiving_hello: false,
         }
     }
+
+    pub(crate) fn hellos(&self) -> &Hellos {
+        &self.hellos
+    }
+
+    pub(crate) fn take_hellos(&mut self) -> Hellos {
+        std::mem::take(&mut self.hellos)
+    }
 }
 
 impl<IO> AsyncRead for CorkStream<IO>
@@ -113,6 +154,12 @@ where
                                 tracing::trace!(
                                     "read header: typ={typ:?}, version={version:?}, len={len}"
                                 );
+                                if typ == rustls::ContentType::Handshake
+                                    && this.hellos.received.is_none()
+                                {
+                                    this.hellos.received = Some(Vec::with_capacity(len as usize));
+                                    this.receiving_hello = true;
+                                }
                                 *state = State::ReadPayload {
                                     msg_size: len as usize,
                                     offset: 0,
@@ -139,8 +186,14 @@ where
 
                         tracing::trace!("read {} bytes off of payload", rest.filled().len());
                         *offset += rest.filled().len();
+                        if let (true, Some(hello)) =
+                            (this.receiving_hello, this.hellos.received.as_mut())
+                        {
+                            hello.extend_from_slice(rest.filled());
+                        }
 
                         if *offset == *msg_size {
+                            this.receiving_hello = false;
                             tracing::trace!("read full payload (all {} bytes)", *offset);
                             *state = State::ReadHeader {
                                 header_buf: Default::default(),
@@ -175,6 +228,24 @@ where
     }
 }
 
+impl<IO> AsRawFd for CorkStream<IO>
+where
+    IO: AsRawFd,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        self.io.as_raw_fd()
+    }
+}
+
+impl<IO> AsFd for CorkStream<IO>
+where
+    IO: AsFd,
+{
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.io.as_fd()
+    }
+}
+
 impl<IO> AsyncWrite for CorkStream<IO>
 where
     IO: AsyncWrite,
@@ -185,7 +256,11 @@ where
         cx: &mut task::Context<'_>,
         buf: &[u8],
     ) -> task::Poll<io::Result<usize>> {
-        let io = unsafe { self.map_unchecked_mut(|s| &mut s.io) };
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.hellos.sent.is_none() {
+            this.hellos.sent = Some(first_handshake_record(buf).unwrap_or_default().to_vec());
+        }
+        let io = unsafe { Pin::new_unchecked(&mut this.io) };
         io.poll_write(cx, buf)
     }
 
@@ -205,6 +280,15 @@ where
     }
 }
 
+/// The payload of the handshake record `buf` starts with, if it holds all of it
+fn first_handshake_record(buf: &[u8]) -> Option<&[u8]> {
+    let header: [u8; 5] = buf.get(..5)?.try_into().ok()?;
+    match decode_header(header)? {
+        (rustls::ContentType::Handshake, _, len) => buf.get(5..5 + len as usize),
+        _ => None,
+    }
+}
+
 fn decode_header(b: [u8; 5]) -> Option<(rustls::ContentType, rustls::ProtocolVersion, u16)> {
     let typ = rustls::ContentType::read_bytes(&b[0..1]).ok()?;
     let version = rustls::ProtocolVersion::read_bytes(&b[1..3]).ok()?;
@@ -0,0 +1,184 @@
+use std::{
+    io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    pin::Pin,
+    task,
+};
+
+use tokio::io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{AsyncReadReady, KtlsStream};
+
+/// A socket driven by tokio's [AsyncFd] rather than a `tokio::net` type, so
+/// that sockets coming from elsewhere (inherited fds, sockets built with
+/// `socket2`, `accept4` called from C code...) can be wrapped in a
+/// [KtlsStream] too.
+pub struct FdStream {
+    inner: AsyncFd<OwnedFd>,
+}
+
+impl FdStream {
+    /// Registers `fd` with the tokio reactor, switching it to non-blocking
+    /// mode. Must be called from within a tokio runtime.
+    pub fn new(fd: OwnedFd) -> io::Result<Self> {
+        crate::ffi::set_nonblocking(fd.as_raw_fd(), true)?;
+        Ok(Self {
+            inner: AsyncFd::new(fd)?,
+        })
+    }
+
+    /// Deregisters the socket from the tokio reactor and returns it. The fd
+    /// is left in non-blocking mode.
+    pub fn into_owned_fd(self) -> OwnedFd {
+        self.inner.into_inner()
+    }
+}
+
+impl KtlsStream<FdStream> {
+    /// Build a [KtlsStream] out of a socket kTLS was already configured on,
+    /// plus whatever plaintext was drained from the TLS library beforehand.
+    pub fn from_owned_fd(fd: OwnedFd, drained: Option<Vec<u8>>) -> io::Result<Self> {
+        Ok(Self::new(FdStream::new(fd)?, drained))
+    }
+
+    /// See [KtlsStream::into_blocking_fd](struct.KtlsStream.html#method.into_blocking_fd)
+    pub fn into_blocking_fd(self) -> io::Result<(OwnedFd, Option<Vec<u8>>)> {
+        let (drained, inner) = self.into_remaining()?;
+        let fd = inner.into_owned_fd();
+        crate::ffi::set_nonblocking(fd.as_raw_fd(), false)?;
+        Ok((fd, drained))
+    }
+}
+
+impl AsyncRead for FdStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        loop {
+            let mut guard = futures::ready!(self.inner.poll_read_ready(cx))?;
+
+            let unfilled = buf.initialize_unfilled();
+            let res = guard.try_io(|inner| {
+                let ret = unsafe {
+                    libc::read(
+                        inner.as_raw_fd(),
+                        unfilled.as_mut_ptr() as *mut libc::c_void,
+                        unfilled.len(),
+                    )
+                };
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(ret as usize)
+            });
+
+            match res {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return task::Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return task::Poll::Ready(Err(e)),
+                // readiness was cleared, wait for the next event
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncReadReady for FdStream {
+    fn poll_read_ready(&self, cx: &mut task::Context<'_>) -> task::Poll<io::Result<()>> {
+        self.inner.poll_read_ready(cx).map_ok(|_guard| ())
+    }
+}
+
+impl AsyncWrite for FdStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> task::Poll<io::Result<usize>> {
+        loop {
+            let mut guard = futures::ready!(self.inner.poll_write_ready(cx))?;
+
+            let res = guard.try_io(|inner| {
+                let ret = unsafe {
+                    libc::send(
+                        inner.as_raw_fd(),
+                        buf.as_ptr() as *const libc::c_void,
+                        buf.len(),
+                        libc::MSG_NOSIGNAL,
+                    )
+                };
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(ret as usize)
+            });
+
+            match res {
+                Ok(res) => return task::Poll::Ready(res),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> task::Poll<io::Result<usize>> {
+        loop {
+            let mut guard = futures::ready!(self.inner.poll_write_ready(cx))?;
+
+            let res = guard.try_io(|inner| {
+                // SAFETY: IoSlice is guaranteed to be ABI compatible with iovec
+                let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+                msg.msg_iov = bufs.as_ptr() as *mut libc::iovec;
+                msg.msg_iovlen = std::cmp::min(bufs.len(), libc::UIO_MAXIOV as usize) as _;
+                let ret = unsafe { libc::sendmsg(inner.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+                if ret < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(ret as usize)
+            });
+
+            match res {
+                Ok(res) => return task::Poll::Ready(res),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut task::Context<'_>) -> task::Poll<io::Result<()>> {
+        task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut task::Context<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        let ret = unsafe { libc::shutdown(self.inner.as_raw_fd(), libc::SHUT_WR) };
+        if ret < 0 {
+            return task::Poll::Ready(Err(io::Error::last_os_error()));
+        }
+        task::Poll::Ready(Ok(()))
+    }
+}
+
+impl AsRawFd for FdStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsFd for FdStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.get_ref().as_fd()
+    }
+}
@@ -1,15 +1,16 @@
 use std::os::unix::prelude::RawFd;
 
 use ktls_sys::bindings as ktls;
+pub(crate) use rustls::internal::msgs::enums::AlertLevel;
 use rustls::{
-    internal::msgs::{enums::AlertLevel, message::Message},
-    AlertDescription, ConnectionTrafficSecrets, SupportedCipherSuite,
+    internal::msgs::message::Message, AlertDescription, ConnectionTrafficSecrets,
+    SupportedCipherSuite,
 };
 
-pub(crate) const TLS_1_2_VERSION_NUMBER: u16 = (((ktls::TLS_1_2_VERSION_MAJOR & 0xFF) as u16) << 8)
+pub const TLS_1_2_VERSION_NUMBER: u16 = (((ktls::TLS_1_2_VERSION_MAJOR & 0xFF) as u16) << 8)
     | ((ktls::TLS_1_2_VERSION_MINOR & 0xFF) as u16);
 
-pub(crate) const TLS_1_3_VERSION_NUMBER: u16 = (((ktls::TLS_1_3_VERSION_MAJOR & 0xFF) as u16) << 8)
+pub const TLS_1_3_VERSION_NUMBER: u16 = (((ktls::TLS_1_3_VERSION_MAJOR & 0xFF) as u16) << 8)
     | ((ktls::TLS_1_3_VERSION_MINOR & 0xFF) as u16);
 
 /// `setsockopt` level constant: TCP
@@ -27,6 +28,14 @@ const TLS_TX: libc::c_int = 1;
 /// `setsockopt` SOL_TLS level constant: receive (read)
 const TLX_RX: libc::c_int = 2;
 
+/// `setsockopt` SOL_TLS level constant: sendfile without copying the file
+/// pages, which must not change while in flight (Linux 5.19)
+const TLS_TX_ZEROCOPY_RO: libc::c_int = 3;
+
+/// `setsockopt` SOL_TLS level constant: promise that TLS 1.3 records carry no
+/// padding, to decrypt them in place (Linux 6.0)
+const TLS_RX_EXPECT_NO_PAD: libc::c_int = 4;
+
 pub fn setup_ulp(fd: RawFd) -> std::io::Result<()> {
     unsafe {
         if libc::setsockopt(
@@ -44,6 +53,28 @@ pub fn setup_ulp(fd: RawFd) -> std::io::Result<()> {
     Ok(())
 }
 
+/// Returns true if the `tls` ULP is already attached to the socket
+pub fn has_tls_ulp(fd: RawFd) -> std::io::Result<bool> {
+    let mut name = [0u8; 16];
+    let mut len = name.len() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            SOL_TCP,
+            TCP_ULP,
+            name.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let name = &name[..len as usize];
+    let name = name.split(|&b| b == 0).next().unwrap_or_default();
+    Ok(name == b"tls")
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum Direction {
     // Transmit
@@ -78,7 +109,6 @@ impl_crypto_info_raw!(
     ktls::tls12_crypto_info_sm4_ccm
 );
 
-#[allow(dead_code)]
 pub enum CryptoInfo {
     AesGcm128(ktls::tls12_crypto_info_aes_gcm_128),
     AesGcm256(ktls::tls12_crypto_info_aes_gcm_256),
@@ -124,6 +154,12 @@ pub enum KtlsCompatibilityError {
 
     #[error("wrong size iv")]
     WrongSizeIv,
+
+    #[error("wrong size salt")]
+    WrongSizeSalt,
+
+    #[error("cipher type not supported with kTLS: {0}")]
+    UnsupportedCipherType(u16),
 }
 
 impl CryptoInfo {
@@ -174,19 +210,177 @@ impl CryptoInfo {
                     rec_seq: seq.to_be_bytes(),
                 })
             }
+            // `ConnectionTrafficSecrets` is `#[non_exhaustive]`: any variant
+            // rustls adds in the future ends up here instead of panicking.
             _ => {
+                tracing::warn!(
+                    ?cipher_suite,
+                    "rustls extracted secrets of a kind the ktls crate doesn't know about"
+                );
                 return Err(KtlsCompatibilityError::UnsupportedCipherSuite(cipher_suite));
             }
         })
     }
 }
 
+impl CryptoInfo {
+    /// Build a `CryptoInfo` out of raw key material, for TLS stacks other
+    /// than rustls. `version` is the TLS version number (e.g. `0x0304`) and
+    /// `cipher_type` one of the kernel's `TLS_CIPHER_*` constants.
+    pub fn from_raw(
+        version: u16,
+        cipher_type: u16,
+        key: &[u8],
+        iv: &[u8],
+        salt: &[u8],
+        seq: u64,
+    ) -> Result<CryptoInfo, KtlsCompatibilityError> {
+        let info = ktls::tls_crypto_info {
+            version,
+            cipher_type,
+        };
+        let key_err = |_| KtlsCompatibilityError::WrongSizeKey;
+        let iv_err = |_| KtlsCompatibilityError::WrongSizeIv;
+        let salt_err = |_| KtlsCompatibilityError::WrongSizeSalt;
+
+        Ok(match u32::from(cipher_type) {
+            ktls::TLS_CIPHER_AES_GCM_128 => {
+                CryptoInfo::AesGcm128(ktls::tls12_crypto_info_aes_gcm_128 {
+                    info,
+                    iv: iv.try_into().map_err(iv_err)?,
+                    key: key.try_into().map_err(key_err)?,
+                    salt: salt.try_into().map_err(salt_err)?,
+                    rec_seq: seq.to_be_bytes(),
+                })
+            }
+            ktls::TLS_CIPHER_AES_GCM_256 => {
+                CryptoInfo::AesGcm256(ktls::tls12_crypto_info_aes_gcm_256 {
+                    info,
+                    iv: iv.try_into().map_err(iv_err)?,
+                    key: key.try_into().map_err(key_err)?,
+                    salt: salt.try_into().map_err(salt_err)?,
+                    rec_seq: seq.to_be_bytes(),
+                })
+            }
+            ktls::TLS_CIPHER_AES_CCM_128 => {
+                CryptoInfo::AesCcm128(ktls::tls12_crypto_info_aes_ccm_128 {
+                    info,
+                    iv: iv.try_into().map_err(iv_err)?,
+                    key: key.try_into().map_err(key_err)?,
+                    salt: salt.try_into().map_err(salt_err)?,
+                    rec_seq: seq.to_be_bytes(),
+                })
+            }
+            ktls::TLS_CIPHER_CHACHA20_POLY1305 => {
+                if !salt.is_empty() {
+                    return Err(KtlsCompatibilityError::WrongSizeSalt);
+                }
+                CryptoInfo::Chacha20Poly1305(ktls::tls12_crypto_info_chacha20_poly1305 {
+                    info,
+                    iv: iv.try_into().map_err(iv_err)?,
+                    key: key.try_into().map_err(key_err)?,
+                    salt: ktls::__IncompleteArrayField::new(),
+                    rec_seq: seq.to_be_bytes(),
+                })
+            }
+            ktls::TLS_CIPHER_SM4_GCM => CryptoInfo::Sm4Gcm(ktls::tls12_crypto_info_sm4_gcm {
+                info,
+                iv: iv.try_into().map_err(iv_err)?,
+                key: key.try_into().map_err(key_err)?,
+                salt: salt.try_into().map_err(salt_err)?,
+                rec_seq: seq.to_be_bytes(),
+            }),
+            ktls::TLS_CIPHER_SM4_CCM => CryptoInfo::Sm4Ccm(ktls::tls12_crypto_info_sm4_ccm {
+                info,
+                iv: iv.try_into().map_err(iv_err)?,
+                key: key.try_into().map_err(key_err)?,
+                salt: salt.try_into().map_err(salt_err)?,
+                rec_seq: seq.to_be_bytes(),
+            }),
+            _ => return Err(KtlsCompatibilityError::UnsupportedCipherType(cipher_type)),
+        })
+    }
+}
+
+/// Typed constructors, one per cipher the kernel supports: the key material
+/// sizes are checked at compile time. `version` is the TLS version number,
+/// [TLS_1_2_VERSION_NUMBER] or [TLS_1_3_VERSION_NUMBER].
+impl CryptoInfo {
+    pub fn aes_gcm_128(version: u16, key: [u8; 16], iv: [u8; 8], salt: [u8; 4], seq: u64) -> Self {
+        CryptoInfo::AesGcm128(ktls::tls12_crypto_info_aes_gcm_128 {
+            info: crypto_info_header(version, ktls::TLS_CIPHER_AES_GCM_128),
+            iv,
+            key,
+            salt,
+            rec_seq: seq.to_be_bytes(),
+        })
+    }
+
+    pub fn aes_gcm_256(version: u16, key: [u8; 32], iv: [u8; 8], salt: [u8; 4], seq: u64) -> Self {
+        CryptoInfo::AesGcm256(ktls::tls12_crypto_info_aes_gcm_256 {
+            info: crypto_info_header(version, ktls::TLS_CIPHER_AES_GCM_256),
+            iv,
+            key,
+            salt,
+            rec_seq: seq.to_be_bytes(),
+        })
+    }
+
+    pub fn aes_ccm_128(version: u16, key: [u8; 16], iv: [u8; 8], salt: [u8; 4], seq: u64) -> Self {
+        CryptoInfo::AesCcm128(ktls::tls12_crypto_info_aes_ccm_128 {
+            info: crypto_info_header(version, ktls::TLS_CIPHER_AES_CCM_128),
+            iv,
+            key,
+            salt,
+            rec_seq: seq.to_be_bytes(),
+        })
+    }
+
+    pub fn chacha20_poly1305(version: u16, key: [u8; 32], iv: [u8; 12], seq: u64) -> Self {
+        CryptoInfo::Chacha20Poly1305(ktls::tls12_crypto_info_chacha20_poly1305 {
+            info: crypto_info_header(version, ktls::TLS_CIPHER_CHACHA20_POLY1305),
+            iv,
+            key,
+            salt: ktls::__IncompleteArrayField::new(),
+            rec_seq: seq.to_be_bytes(),
+        })
+    }
+
+    pub fn sm4_gcm(version: u16, key: [u8; 16], iv: [u8; 8], salt: [u8; 4], seq: u64) -> Self {
+        CryptoInfo::Sm4Gcm(ktls::tls12_crypto_info_sm4_gcm {
+            info: crypto_info_header(version, ktls::TLS_CIPHER_SM4_GCM),
+            iv,
+            key,
+            salt,
+            rec_seq: seq.to_be_bytes(),
+        })
+    }
+
+    pub fn sm4_ccm(version: u16, key: [u8; 16], iv: [u8; 8], salt: [u8; 4], seq: u64) -> Self {
+        CryptoInfo::Sm4Ccm(ktls::tls12_crypto_info_sm4_ccm {
+            info: crypto_info_header(version, ktls::TLS_CIPHER_SM4_CCM),
+            iv,
+            key,
+            salt,
+            rec_seq: seq.to_be_bytes(),
+        })
+    }
+}
+
+fn crypto_info_header(version: u16, cipher_type: u32) -> ktls::tls_crypto_info {
+    ktls::tls_crypto_info {
+        version,
+        cipher_type: cipher_type as _,
+    }
+}
+
 pub fn setup_tls_info(fd: RawFd, dir: Direction, info: CryptoInfo) -> Result<(), crate::Error> {
     let ret = unsafe { libc::setsockopt(fd, SOL_TLS, dir.into(), info.as_ptr(), info.size() as _) };
     if ret < 0 {
-        return Err(crate::Error::TlsCryptoInfoError(
-            std::io::Error::last_os_error(),
-        ));
+        return Err(crate::Error::Setsockopt {
+            direction: dir,
+            source: std::io::Error::last_os_error(),
+        });
     }
     Ok(())
 }
@@ -218,8 +412,16 @@ impl<const N: usize> Cmsg<N> {
 }
 
 pub fn send_close_notify(fd: RawFd) -> std::io::Result<()> {
+    send_alert(fd, AlertLevel::Warning, AlertDescription::CloseNotify)
+}
+
+pub fn send_alert(
+    fd: RawFd,
+    level: AlertLevel,
+    description: AlertDescription,
+) -> std::io::Result<()> {
     let mut data = vec![];
-    Message::build_alert(AlertLevel::Warning, AlertDescription::CloseNotify)
+    Message::build_alert(level, description)
         .payload
         .encode(&mut data);
 
@@ -244,3 +446,227 @@ pub fn send_close_notify(fd: RawFd) -> std::io::Result<()> {
     }
     Ok(())
 }
+
+#[derive(Clone, Copy, Debug)]
+pub enum TlsOption {
+    TxZerocopyRo,
+    RxExpectNoPad,
+}
+
+/// Only valid once keys are installed for the option's direction
+pub fn set_tls_option(fd: RawFd, option: TlsOption, enabled: bool) -> std::io::Result<()> {
+    let name = match option {
+        TlsOption::TxZerocopyRo => TLS_TX_ZEROCOPY_RO,
+        TlsOption::RxExpectNoPad => TLS_RX_EXPECT_NO_PAD,
+    };
+    let value = enabled as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_TLS,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as _,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn set_nonblocking(fd: RawFd, nonblocking: bool) -> std::io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let flags = if nonblocking {
+            flags | libc::O_NONBLOCK
+        } else {
+            flags & !libc::O_NONBLOCK
+        };
+        if libc::fcntl(fd, libc::F_SETFL, flags) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn sendfile(out_fd: RawFd, in_fd: RawFd, offset: u64, count: usize) -> std::io::Result<usize> {
+    let mut offset = offset as libc::off_t;
+    let ret = unsafe { libc::sendfile(out_fd, in_fd, &mut offset, count) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+/// Sends all of `buf`, `chunk_size` bytes per call at most, waiting for the
+/// socket to be writable whenever it's full, even in non-blocking mode
+pub fn send_all_blocking(fd: RawFd, mut buf: &[u8], chunk_size: usize) -> std::io::Result<()> {
+    while !buf.is_empty() {
+        let len = std::cmp::min(buf.len(), chunk_size);
+        let ret = unsafe {
+            libc::send(
+                fd,
+                buf.as_ptr() as *const libc::c_void,
+                len,
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            match err.kind() {
+                std::io::ErrorKind::Interrupted => continue,
+                std::io::ErrorKind::WouldBlock => {
+                    let mut pollfd = libc::pollfd {
+                        fd,
+                        events: libc::POLLOUT,
+                        revents: 0,
+                    };
+                    if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+                        let err = std::io::Error::last_os_error();
+                        if err.kind() != std::io::ErrorKind::Interrupted {
+                            return Err(err);
+                        }
+                    }
+                    continue;
+                }
+                _ => return Err(err),
+            }
+        }
+        if ret == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[ret as usize..];
+    }
+    Ok(())
+}
+
+/// Mirror of the kernel's `struct tcp_info` up to `tcpi_delivery_rate` (4.9+).
+/// The kernel copies as much as it knows about, the rest stays zeroed.
+#[repr(C)]
+#[derive(Default)]
+pub struct RawTcpInfo {
+    pub state: u8,
+    pub ca_state: u8,
+    pub retransmits: u8,
+    pub probes: u8,
+    pub backoff: u8,
+    pub options: u8,
+    pub snd_rcv_wscale: u8,
+    pub delivery_rate_app_limited: u8,
+
+    pub rto: u32,
+    pub ato: u32,
+    pub snd_mss: u32,
+    pub rcv_mss: u32,
+
+    pub unacked: u32,
+    pub sacked: u32,
+    pub lost: u32,
+    pub retrans: u32,
+    pub fackets: u32,
+
+    pub last_data_sent: u32,
+    pub last_ack_sent: u32,
+    pub last_data_recv: u32,
+    pub last_ack_recv: u32,
+
+    pub pmtu: u32,
+    pub rcv_ssthresh: u32,
+    pub rtt: u32,
+    pub rttvar: u32,
+    pub snd_ssthresh: u32,
+    pub snd_cwnd: u32,
+    pub advmss: u32,
+    pub reordering: u32,
+
+    pub rcv_rtt: u32,
+    pub rcv_space: u32,
+
+    pub total_retrans: u32,
+
+    pub pacing_rate: u64,
+    pub max_pacing_rate: u64,
+    pub bytes_acked: u64,
+    pub bytes_received: u64,
+    pub segs_out: u32,
+    pub segs_in: u32,
+
+    pub notsent_bytes: u32,
+    pub min_rtt: u32,
+    pub data_segs_in: u32,
+    pub data_segs_out: u32,
+
+    pub delivery_rate: u64,
+}
+
+pub fn get_tcp_info(fd: RawFd) -> std::io::Result<RawTcpInfo> {
+    let mut info = RawTcpInfo::default();
+    let mut len = std::mem::size_of::<RawTcpInfo>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            SOL_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(info)
+}
+
+/// The TLS version and cipher type configured for `dir`, `None` if it wasn't
+/// configured. Only the header of the crypto info is asked for, so the kernel
+/// doesn't hand the keys back.
+pub fn get_crypto_info(fd: RawFd, dir: Direction) -> std::io::Result<Option<(u16, u16)>> {
+    let mut info = ktls::tls_crypto_info {
+        version: 0,
+        cipher_type: 0,
+    };
+    let mut len = std::mem::size_of::<ktls::tls_crypto_info>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            SOL_TLS,
+            dir.into(),
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        let err = std::io::Error::last_os_error();
+        // EBUSY: no keys for that direction yet
+        if err.raw_os_error() == Some(libc::EBUSY) {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    Ok(Some((info.version, info.cipher_type)))
+}
+
+/// SIOCINQ: bytes in the socket's receive queue
+pub fn bytes_in_recv_queue(fd: RawFd) -> std::io::Result<usize> {
+    ioctl_queue_len(fd, libc::FIONREAD)
+}
+
+/// SIOCOUTQ: bytes in the socket's send queue, not yet acked by the peer
+pub fn bytes_in_send_queue(fd: RawFd) -> std::io::Result<usize> {
+    ioctl_queue_len(fd, libc::TIOCOUTQ)
+}
+
+fn ioctl_queue_len(fd: RawFd, request: libc::Ioctl) -> std::io::Result<usize> {
+    let mut len: libc::c_int = 0;
+    let ret = unsafe { libc::ioctl(fd, request, &mut len) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(len as usize)
+}
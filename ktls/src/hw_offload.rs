@@ -0,0 +1,248 @@
+use std::{
+    ffi::CStr,
+    io,
+    net::IpAddr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+// From linux/ethtool_netlink.h
+const ETHTOOL_GENL_NAME: &[u8] = b"ethtool\0";
+const ETHTOOL_GENL_VERSION: u8 = 1;
+const ETHTOOL_MSG_FEATURES_GET: u8 = 11;
+const ETHTOOL_A_FEATURES_HEADER: u16 = 1;
+const ETHTOOL_A_FEATURES_ACTIVE: u16 = 4;
+const ETHTOOL_A_HEADER_DEV_NAME: u16 = 2;
+const ETHTOOL_A_BITSET_NOMASK: u16 = 1;
+const ETHTOOL_A_BITSET_BITS: u16 = 3;
+const ETHTOOL_A_BITSET_BITS_BIT: u16 = 1;
+const ETHTOOL_A_BITSET_BIT_NAME: u16 = 2;
+const ETHTOOL_A_BITSET_BIT_VALUE: u16 = 3;
+
+/// Big enough for a verbose features reply, which lists every feature by name
+const RECV_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Whether a NIC encrypts kTLS records itself (`tls-hw-tx-offload`) or
+/// decrypts them (`tls-hw-rx-offload`), instead of the kernel's software
+/// implementation. Offloaded connections use it when the cipher matches what
+/// the device supports, see the driver's documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HwOffload {
+    pub interface: String,
+    pub tx: bool,
+    pub rx: bool,
+}
+
+impl HwOffload {
+    /// For the interface the route to `dest` goes out of
+    pub fn detect(dest: IpAddr) -> io::Result<Self> {
+        let interface = egress_interface(dest)?;
+        Self::detect_on(&interface)
+    }
+
+    /// For the named interface (e.g. `eth0`), through ethtool netlink (Linux
+    /// 5.6+)
+    pub fn detect_on(interface: &str) -> io::Result<Self> {
+        let active = active_features(interface)?;
+        Ok(Self {
+            interface: interface.to_owned(),
+            tx: active.iter().any(|f| f == "tls-hw-tx-offload"),
+            rx: active.iter().any(|f| f == "tls-hw-rx-offload"),
+        })
+    }
+}
+
+/// Asks the kernel for the route to `dest` (`ip route get`), and names its
+/// output interface
+fn egress_interface(dest: IpAddr) -> io::Result<String> {
+    let (family, addr) = match dest {
+        IpAddr::V4(ip) => (libc::AF_INET, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (libc::AF_INET6, ip.octets().to_vec()),
+    };
+
+    // struct rtmsg: family, dst_len, src_len, tos, table, protocol, scope,
+    // type, then u32 flags
+    let mut req = vec![family as u8, addr.len() as u8 * 8, 0, 0, 0, 0, 0, 0];
+    req.extend_from_slice(&0u32.to_ne_bytes());
+    put_attr(&mut req, libc::RTA_DST, &addr);
+
+    let mut sock = Netlink::open(libc::NETLINK_ROUTE)?;
+    let reply = sock.request(libc::RTM_GETROUTE, &req)?;
+    let oif = attrs(reply.get(12..).unwrap_or_default())
+        .find(|(ty, _)| *ty == libc::RTA_OIF)
+        .and_then(|(_, data)| Some(u32::from_ne_bytes(data.try_into().ok()?)))
+        .ok_or_else(|| invalid_data("route without an output interface"))?;
+
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    if unsafe { libc::if_indextoname(oif, name.as_mut_ptr()) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { CStr::from_ptr(name.as_ptr()) }
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// Names of the features active on `interface`
+fn active_features(interface: &str) -> io::Result<Vec<String>> {
+    let mut sock = Netlink::open(libc::NETLINK_GENERIC)?;
+    let family = ethtool_family(&mut sock)?;
+
+    let mut req = vec![ETHTOOL_MSG_FEATURES_GET, ETHTOOL_GENL_VERSION, 0, 0];
+    let mut header = Vec::new();
+    let mut name = interface.as_bytes().to_vec();
+    name.push(0);
+    put_attr(&mut header, ETHTOOL_A_HEADER_DEV_NAME, &name);
+    put_attr(
+        &mut req,
+        ETHTOOL_A_FEATURES_HEADER | libc::NLA_F_NESTED as u16,
+        &header,
+    );
+
+    let reply = sock.request(family, &req)?;
+    let bitset = attrs(reply.get(4..).unwrap_or_default())
+        .find(|(ty, _)| *ty == ETHTOOL_A_FEATURES_ACTIVE)
+        .ok_or_else(|| invalid_data("features reply without active features"))?
+        .1;
+
+    // Without a mask, the bits listed are the set ones, otherwise each one
+    // says whether it's set
+    let nomask = attrs(bitset).any(|(ty, _)| ty == ETHTOOL_A_BITSET_NOMASK);
+    let Some((_, bits)) = attrs(bitset).find(|(ty, _)| *ty == ETHTOOL_A_BITSET_BITS) else {
+        return Ok(Vec::new());
+    };
+
+    Ok(attrs(bits)
+        .filter(|(ty, _)| *ty == ETHTOOL_A_BITSET_BITS_BIT)
+        .filter(|(_, bit)| nomask || attrs(bit).any(|(ty, _)| ty == ETHTOOL_A_BITSET_BIT_VALUE))
+        .filter_map(|(_, bit)| {
+            let (_, name) = attrs(bit).find(|(ty, _)| *ty == ETHTOOL_A_BITSET_BIT_NAME)?;
+            let name = CStr::from_bytes_until_nul(name).ok()?;
+            Some(name.to_string_lossy().into_owned())
+        })
+        .collect())
+}
+
+/// The generic netlink family id of ethtool, which is allocated at runtime
+fn ethtool_family(sock: &mut Netlink) -> io::Result<u16> {
+    let mut req = vec![libc::CTRL_CMD_GETFAMILY as u8, 1, 0, 0];
+    put_attr(
+        &mut req,
+        libc::CTRL_ATTR_FAMILY_NAME as u16,
+        ETHTOOL_GENL_NAME,
+    );
+
+    let reply = sock.request(libc::GENL_ID_CTRL as u16, &req)?;
+    let id = attrs(reply.get(4..).unwrap_or_default())
+        .find(|(ty, _)| *ty == libc::CTRL_ATTR_FAMILY_ID as u16)
+        .and_then(|(_, data)| Some(u16::from_ne_bytes(data.try_into().ok()?)));
+    id.ok_or_else(|| invalid_data("no ethtool netlink family id"))
+}
+
+struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    fn open(protocol: libc::c_int) -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                protocol,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            seq: 0,
+        })
+    }
+
+    /// Sends a `msg_type` request and returns the payload of its reply,
+    /// without the `nlmsghdr`
+    fn request(&mut self, msg_type: u16, payload: &[u8]) -> io::Result<Vec<u8>> {
+        self.seq += 1;
+        let len = NLMSG_HDRLEN + payload.len();
+        let mut msg = Vec::with_capacity(len);
+        msg.extend_from_slice(&(len as u32).to_ne_bytes());
+        msg.extend_from_slice(&msg_type.to_ne_bytes());
+        msg.extend_from_slice(&(libc::NLM_F_REQUEST as u16).to_ne_bytes());
+        msg.extend_from_slice(&self.seq.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(payload);
+
+        let sent = unsafe { libc::send(self.fd.as_raw_fd(), msg.as_ptr().cast(), msg.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+        loop {
+            let n =
+                unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut rest = &buf[..n as usize];
+            while rest.len() >= NLMSG_HDRLEN {
+                let len = u32::from_ne_bytes(rest[0..4].try_into().unwrap()) as usize;
+                let ty = u16::from_ne_bytes(rest[4..6].try_into().unwrap());
+                let seq = u32::from_ne_bytes(rest[8..12].try_into().unwrap());
+                if len < NLMSG_HDRLEN || len > rest.len() {
+                    return Err(invalid_data("truncated netlink message"));
+                }
+                let body = &rest[NLMSG_HDRLEN..len];
+                rest = &rest[align(len).min(rest.len())..];
+
+                if seq != self.seq {
+                    continue;
+                }
+                if ty == libc::NLMSG_ERROR as u16 {
+                    let errno = body
+                        .get(..4)
+                        .map_or(0, |e| i32::from_ne_bytes(e.try_into().unwrap()));
+                    return Err(io::Error::from_raw_os_error(-errno));
+                }
+                return Ok(body.to_vec());
+            }
+        }
+    }
+}
+
+const NLMSG_HDRLEN: usize = 16;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn put_attr(buf: &mut Vec<u8>, ty: u16, data: &[u8]) {
+    buf.extend_from_slice(&(4 + data.len() as u16).to_ne_bytes());
+    buf.extend_from_slice(&ty.to_ne_bytes());
+    buf.extend_from_slice(data);
+    buf.resize(align(buf.len()), 0);
+}
+
+/// `(type, payload)` of each attribute in `buf`, with the nested and
+/// byte-order flags masked off the type
+fn attrs(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < 4 {
+            return None;
+        }
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        let ty = u16::from_ne_bytes([buf[2], buf[3]]) & libc::NLA_TYPE_MASK as u16;
+        if len < 4 || len > buf.len() {
+            return None;
+        }
+        let data = &buf[4..len];
+        buf = &buf[align(len).min(buf.len())..];
+        Some((ty, data))
+    })
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,101 @@
+use std::{
+    net::IpAddr,
+    os::fd::{AsRawFd, RawFd},
+};
+
+use crate::{
+    ffi::{self, CryptoInfo, Direction, TlsOption},
+    probe::{kernel_version, self_connected_socket},
+    zero_secrets, CompatibleCiphers, HwOffload, ProbeStrategy,
+};
+
+/// What the running kernel can do with kTLS, e.g. to log at startup. Each
+/// feature is probed on scratch loopback sockets rather than guessed from
+/// the kernel version, so backports and distribution kernels are reported
+/// correctly.
+#[derive(Debug, Default)]
+pub struct KernelSupport {
+    /// `(major, minor)`, `None` if `uname` couldn't be parsed
+    pub kernel_version: Option<(u32, u32)>,
+    /// The `tls` ULP can be attached (which loads the module if needed)
+    pub tls_module: bool,
+    /// Per version, cipher and direction
+    pub ciphers: CompatibleCiphers,
+    /// TLS 1.3 keys can be replaced once installed, to follow KeyUpdates
+    pub rekey: bool,
+    /// `sendfile` can skip copying file pages (`TLS_TX_ZEROCOPY_RO`)
+    pub tx_zerocopy: bool,
+    /// TLS 1.3 records can be decrypted in place (`TLS_RX_EXPECT_NO_PAD`)
+    pub rx_expect_no_pad: bool,
+    /// The NIC's TLS offload, only filled by [KernelSupport::detect_for]
+    pub hw_offload: Option<HwOffload>,
+}
+
+impl KernelSupport {
+    /// Runs every probe, with blocking sockets and without binding a listener
+    pub fn detect() -> Self {
+        let kernel_version = kernel_version().ok();
+        let tls_module = crate::ensure_tls_module().is_ok();
+        if !tls_module {
+            return Self {
+                kernel_version,
+                ..Default::default()
+            };
+        }
+
+        let ciphers = CompatibleCiphers::new_with(ProbeStrategy::SelfConnect).unwrap_or_else(|e| {
+            tracing::warn!(%e, "failed to probe kTLS ciphers");
+            Default::default()
+        });
+
+        Self {
+            kernel_version,
+            tls_module,
+            ciphers,
+            rekey: with_tls13_socket(|fd| {
+                install_keys(fd, Direction::Tx) && install_keys(fd, Direction::Tx)
+            }),
+            tx_zerocopy: with_tls13_socket(|fd| {
+                install_keys(fd, Direction::Tx)
+                    && ffi::set_tls_option(fd, TlsOption::TxZerocopyRo, true).is_ok()
+            }),
+            rx_expect_no_pad: with_tls13_socket(|fd| {
+                install_keys(fd, Direction::Rx)
+                    && ffi::set_tls_option(fd, TlsOption::RxExpectNoPad, true).is_ok()
+            }),
+            hw_offload: None,
+        }
+    }
+
+    /// Like [KernelSupport::detect], plus the hardware offload of the
+    /// interface traffic to `dest` is routed through
+    pub fn detect_for(dest: IpAddr) -> Self {
+        let hw_offload = match HwOffload::detect(dest) {
+            Ok(hw_offload) => Some(hw_offload),
+            Err(e) => {
+                tracing::debug!(%e, %dest, "failed to query NIC TLS offload");
+                None
+            }
+        };
+        Self {
+            hw_offload,
+            ..Self::detect()
+        }
+    }
+}
+
+/// Runs `probe` on a fresh loopback socket with the `tls` ULP attached,
+/// false if that can't be set up
+fn with_tls13_socket(probe: impl FnOnce(RawFd) -> bool) -> bool {
+    let Ok(sock) = self_connected_socket() else {
+        return false;
+    };
+    let fd = sock.as_raw_fd();
+    ffi::setup_ulp(fd).is_ok() && probe(fd)
+}
+
+fn install_keys(fd: RawFd, dir: Direction) -> bool {
+    let suite = rustls::cipher_suite::TLS13_AES_128_GCM_SHA256;
+    let info = CryptoInfo::from_rustls(suite, (0, zero_secrets(suite))).unwrap();
+    ffi::setup_tls_info(fd, dir, info).is_ok()
+}
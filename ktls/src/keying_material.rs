@@ -0,0 +1,72 @@
+use std::fmt;
+
+use rustls::Connection;
+
+use crate::Error;
+
+/// A keying material export (RFC 5705, RFC 8446 section 7.5) to perform while
+/// the rustls connection is still around. The exporter secret can't be
+/// extracted from rustls, so exports have to be requested before offloading,
+/// see [crate::KtlsConfig::with_exports] and
+/// [crate::KtlsStream::export_keying_material].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyingMaterialExport {
+    pub label: Vec<u8>,
+    pub context: Option<Vec<u8>>,
+    pub len: usize,
+}
+
+impl KeyingMaterialExport {
+    pub fn new(label: impl Into<Vec<u8>>, context: Option<Vec<u8>>, len: usize) -> Self {
+        Self {
+            label: label.into(),
+            context,
+            len,
+        }
+    }
+
+    fn matches(&self, label: &[u8], context: Option<&[u8]>, len: usize) -> bool {
+        self.label == label && self.context.as_deref() == context && self.len == len
+    }
+}
+
+/// The result of the exports, kept out of `Debug` output
+#[derive(Clone, Default)]
+pub(crate) struct ExportedKeyingMaterial(Vec<(KeyingMaterialExport, Vec<u8>)>);
+
+impl ExportedKeyingMaterial {
+    pub(crate) fn export(
+        conn: &Connection,
+        exports: &[KeyingMaterialExport],
+    ) -> Result<Self, Error> {
+        let exported = exports
+            .iter()
+            .map(|export| {
+                let material = conn
+                    .export_keying_material(
+                        vec![0u8; export.len],
+                        &export.label,
+                        export.context.as_deref(),
+                    )
+                    .map_err(Error::ExportKeyingMaterial)?;
+                Ok((export.clone(), material))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self(exported))
+    }
+
+    pub(crate) fn get(&self, label: &[u8], context: Option<&[u8]>, len: usize) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(export, _)| export.matches(label, context, len))
+            .map(|(_, material)| &material[..])
+    }
+}
+
+impl fmt::Debug for ExportedKeyingMaterial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(export, _)| export))
+            .finish()
+    }
+}
@@ -0,0 +1,67 @@
+use rustls::ProtocolVersion;
+use std::os::unix::prelude::RawFd;
+
+use ktls_sys::bindings as ktls;
+
+use crate::ffi::{self, Direction};
+
+/// What the kernel says is configured on a kTLS socket, from the `SOL_TLS`
+/// socket options, see [crate::KtlsStream::ktls_info]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KtlsInfo {
+    /// `None` if transmit keys weren't installed
+    pub tx: Option<KtlsDirectionInfo>,
+    /// `None` if receive keys weren't installed
+    pub rx: Option<KtlsDirectionInfo>,
+}
+
+/// What's configured for one direction of a kTLS socket. Whether records are
+/// encrypted by the NIC or in software isn't exposed through socket options,
+/// see `TlsTxDevice` / `TlsTxSw` in `/proc/net/tls_stat` for that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KtlsDirectionInfo {
+    pub protocol_version: ProtocolVersion,
+    pub cipher: KtlsCipher,
+}
+
+/// The kernel's `TLS_CIPHER_*` constants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KtlsCipher {
+    AesGcm128,
+    AesGcm256,
+    AesCcm128,
+    Chacha20Poly1305,
+    Sm4Gcm,
+    Sm4Ccm,
+    Other(u16),
+}
+
+impl From<u16> for KtlsCipher {
+    fn from(cipher_type: u16) -> Self {
+        match u32::from(cipher_type) {
+            ktls::TLS_CIPHER_AES_GCM_128 => Self::AesGcm128,
+            ktls::TLS_CIPHER_AES_GCM_256 => Self::AesGcm256,
+            ktls::TLS_CIPHER_AES_CCM_128 => Self::AesCcm128,
+            ktls::TLS_CIPHER_CHACHA20_POLY1305 => Self::Chacha20Poly1305,
+            ktls::TLS_CIPHER_SM4_GCM => Self::Sm4Gcm,
+            ktls::TLS_CIPHER_SM4_CCM => Self::Sm4Ccm,
+            _ => Self::Other(cipher_type),
+        }
+    }
+}
+
+impl KtlsInfo {
+    pub(crate) fn get(fd: RawFd) -> std::io::Result<Self> {
+        let get = |dir| {
+            let info = ffi::get_crypto_info(fd, dir)?;
+            Ok::<_, std::io::Error>(info.map(|(version, cipher_type)| KtlsDirectionInfo {
+                protocol_version: ProtocolVersion::from(version),
+                cipher: KtlsCipher::from(cipher_type),
+            }))
+        };
+        Ok(Self {
+            tx: get(Direction::Tx)?,
+            rx: get(Direction::Rx)?,
+        })
+    }
+}
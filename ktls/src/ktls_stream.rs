@@ -1,48 +1,328 @@
 use ktls_recvmsg::{recvmsg, ControlMessageOwned, Errno, MsgFlags, SockaddrIn};
 use num_enum::FromPrimitive;
+use rustls::AlertDescription;
+use smallvec::SmallVec;
+use socket2::SockRef;
 use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use std::task::{Context, Poll};
 use std::{
-    io::{self, IoSliceMut},
+    io::{self, IoSlice, IoSliceMut},
     os::unix::prelude::AsRawFd,
     pin::Pin,
     task,
 };
 
-use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf, Ready};
 
-use crate::AsyncReadReady;
+use crate::{
+    keying_material::ExportedKeyingMaterial, split::SharedTcpStream, stats::StreamStats,
+    AsyncReadReady, CloseNotifyOnDrop, ConnectionInfo, ControlRecord, ControlRecordHandler, Error,
+    Extensions,
+};
 
 // A wrapper around `IO` that sends a `close_notify` when shut down or dropped.
 pin_project_lite::pin_project! {
     pub struct KtlsStream<IO>
     where
-        IO: AsRawFd
+        IO: AsFd
     {
         #[pin]
         inner: IO,
-        write_closed: bool,
-        read_closed: bool,
-        drained: Option<(usize, Vec<u8>)>,
+        write_closed: AtomicBool,
+        // behind mutexes so `&KtlsStream` can be read from and written to,
+        // `&mut` access bypasses them
+        read: Mutex<ReadSide>,
+        write: Mutex<WriteSide>,
+        stats: Arc<StreamStats>,
+        info: ConnectionInfo,
+        extensions: Extensions,
+        keying_material: ExportedKeyingMaterial,
     }
 }
 
 impl<IO> KtlsStream<IO>
 where
-    IO: AsRawFd,
+    IO: AsFd,
 {
     pub fn new(inner: IO, drained: Option<Vec<u8>>) -> Self {
         Self {
             inner,
-            write_closed: false,
-            read_closed: false,
-            drained: drained.map(|drained| (0, drained)),
+            write_closed: AtomicBool::new(false),
+            read: Mutex::new(ReadSide::new(drained)),
+            write: Mutex::new(WriteSide::new()),
+            stats: Default::default(),
+            info: Default::default(),
+            extensions: Default::default(),
+            keying_material: Default::default(),
+        }
+    }
+
+    /// In atomic write mode, each `poll_write` either accepts the whole buffer
+    /// or nothing: if the socket only takes part of it, the rest is kept
+    /// aside and sent before anything else, on the next write or flush.
+    ///
+    /// Message-oriented protocols can then write one message per call without
+    /// its records interleaving with another message's across task wakeups.
+    /// Callers must flush before dropping the stream to be sure everything was
+    /// sent.
+    pub fn with_atomic_writes(mut self, atomic_writes: bool) -> Self {
+        get_mut(&mut self.write).atomic_writes = atomic_writes;
+        self
+    }
+
+    /// In coalescing mode, small writes are buffered until there's a full TLS
+    /// record's worth of data or the stream is flushed, so chatty protocols
+    /// don't produce one tiny record per write. Nothing is sent until then:
+    /// callers must flush whenever they expect a reply. Vectored writes
+    /// aren't buffered, the buffer goes out first and they're sent right
+    /// after it, like atomic writes.
+    pub fn with_coalesced_writes(mut self, coalesce_writes: bool) -> Self {
+        get_mut(&mut self.write).coalesce_writes = coalesce_writes;
+        self
+    }
+
+    /// Caps how much plaintext is handed to the kernel per write, and thus
+    /// the size of the TLS records it produces: small records (e.g. 4 KiB)
+    /// can be decrypted by the peer as soon as they arrive, which lowers
+    /// latency for streaming, big ones (up to 16 KiB) have less overhead.
+    /// By default, writes are passed as is and the kernel fills records up
+    /// to 16 KiB.
+    pub fn with_max_record_size(mut self, max_record_size: Option<usize>) -> Self {
+        get_mut(&mut self.write).max_record_size =
+            max_record_size.map(|max| max.clamp(1, COALESCE_LIMIT));
+        self
+    }
+
+    /// In record mode, reads aren't chained to fill the caller's buffer, and
+    /// [KtlsStream::at_record_boundary] tells whether the last read ended on
+    /// a record boundary, to debug framing. This is a hint, not framing: the
+    /// kernel copies every record that's ready into the same read, so a read
+    /// can hold several records, the last one possibly cut short.
+    pub fn with_record_reads(mut self, record_reads: bool) -> Self {
+        get_mut(&mut self.read).record_reads = record_reads;
+        self
+    }
+
+    /// Hands alerts, session tickets, key updates and other non-application
+    /// records to `handler` as they're received. They're otherwise handled
+    /// internally (alerts) or ignored (everything else).
+    pub fn with_control_record_handler(
+        mut self,
+        handler: impl Fn(ControlRecord) + Send + Sync + 'static,
+    ) -> Self {
+        get_mut(&mut self.read).control_record_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// What shutting the stream down sends to the peer, see [ShutdownMode]
+    pub fn with_shutdown_mode(mut self, shutdown_mode: ShutdownMode) -> Self {
+        get_mut(&mut self.write).shutdown_mode = shutdown_mode;
+        self
+    }
+
+    pub(crate) fn with_connection_info(mut self, info: ConnectionInfo) -> Self {
+        self.info = info;
+        self
+    }
+
+    /// Puts `data` before whatever was drained, for plaintext read from
+    /// rustls before the drain itself
+    pub(crate) fn with_data_before_drained(mut self, mut data: Vec<u8>) -> Self {
+        if data.is_empty() {
+            return self;
+        }
+        if let Some(drained) = self.take_drained() {
+            data.extend_from_slice(&drained);
         }
+        get_mut(&mut self.read).drained = Some((0, data));
+        self
+    }
+
+    pub(crate) fn with_keying_material(mut self, keying_material: ExportedKeyingMaterial) -> Self {
+        self.keying_material = keying_material;
+        self
+    }
+
+    /// Returns keying material exported as per RFC 5705 / RFC 8446. Only
+    /// exports requested before offloading are available (see
+    /// [crate::KtlsConfig::with_exports]), anything else fails with
+    /// [Error::KeyingMaterialNotExported].
+    pub fn export_keying_material(
+        &self,
+        label: &[u8],
+        context: Option<&[u8]>,
+        len: usize,
+    ) -> Result<&[u8], Error> {
+        self.keying_material
+            .get(label, context, len)
+            .ok_or(Error::KeyingMaterialNotExported)
+    }
+
+    /// Returns what's known about the offloaded TLS session
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    /// Per-connection data attached by the application, see [Extensions]
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Mutable access to the per-connection data, see [Extensions]
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// The protocol agreed on through ALPN, if any, see
+    /// [ConnectionInfo::alpn_protocol]
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.info.alpn_protocol()
+    }
+
+    /// The certificate chain presented by the peer, see
+    /// [ConnectionInfo::peer_certificates]
+    pub fn peer_certificates(&self) -> Option<&[rustls::Certificate]> {
+        self.info.peer_certificates()
+    }
+
+    /// The SNI server name sent by the client, see
+    /// [ConnectionInfo::server_name]
+    pub fn server_name(&self) -> Option<&str> {
+        self.info.server_name()
     }
 
     /// Return the drained data + the original I/O
-    pub fn into_raw(self) -> (Option<Vec<u8>>, IO) {
-        (self.drained.map(|(_, drained)| drained), self.inner)
+    ///
+    /// What atomic or coalesced writes accepted but didn't send yet is sent
+    /// first, blocking until the socket took it. A failure there means the
+    /// connection is broken, the next operation on `IO` reports it.
+    pub fn into_raw(mut self) -> (Option<Vec<u8>>, IO) {
+        if let Err(e) = self.send_write_backlog() {
+            tracing::warn!("failed to send buffered writes before handing the socket over: {e}");
+        }
+        let drained = into_inner(self.read).drained;
+        (drained.map(|(_, drained)| drained), self.inner)
+    }
+
+    /// Takes the application data rustls had already decrypted when the
+    /// connection was offloaded, and that hasn't been read yet, so it can be
+    /// handed to a parser as is rather than copied through `poll_read`.
+    /// Reads then go straight to the socket.
+    pub fn take_drained(&mut self) -> Option<Vec<u8>> {
+        let (drain_index, mut drained) = get_mut(&mut self.read).drained.take()?;
+        // no new allocation, even when some of it was read already
+        drained.drain(..drain_index);
+        Some(drained)
+    }
+
+    /// Like [KtlsStream::into_raw], but only returns the drained data that
+    /// hasn't been read yet, and fails if the buffered writes couldn't be
+    /// sent
+    pub(crate) fn into_remaining(mut self) -> io::Result<(Option<Vec<u8>>, IO)> {
+        self.send_write_backlog()?;
+        let drained = into_inner(self.read).drained;
+        let drained = drained.map(|(drain_index, mut drained)| {
+            drained.drain(..drain_index);
+            drained
+        });
+        Ok((drained, self.inner))
+    }
+
+    /// Sends what atomic and coalesced writes accepted but the socket didn't
+    /// take yet, blocking until it's all out, so handing the socket over
+    /// doesn't lose it
+    fn send_write_backlog(&mut self) -> io::Result<()> {
+        let write = get_mut(&mut self.write);
+        let Some((index, backlog)) = write.write_backlog.take() else {
+            return Ok(());
+        };
+        if *self.write_closed.get_mut() {
+            return Err(write_closed_with_backlog());
+        }
+        let pending = &backlog[index..];
+        let chunk_size = write.max_record_size.unwrap_or(pending.len());
+        crate::ffi::send_all_blocking(self.inner.as_fd().as_raw_fd(), pending, chunk_size)?;
+        self.stats.record_write(pending.len());
+        Ok(())
+    }
+
+    /// Splits the stream in two, with the read-side state going to the first
+    /// one and the write-side state going to the second one. Both end up
+    /// sharing the same counters, the caller keeps their `write_closed` flags
+    /// in sync.
+    pub(crate) fn split_with<R, W>(
+        self,
+        f: impl FnOnce(IO) -> (R, W),
+    ) -> (KtlsStream<R>, KtlsStream<W>)
+    where
+        R: AsFd,
+        W: AsFd,
+    {
+        let (r, w) = f(self.inner);
+        let read_half = KtlsStream {
+            inner: r,
+            // so the read half can reply to an alert with a close_notify
+            write_closed: AtomicBool::new(false),
+            read: self.read,
+            write: Mutex::new(WriteSide::new()),
+            stats: self.stats.clone(),
+            info: self.info,
+            extensions: self.extensions,
+            keying_material: self.keying_material,
+        };
+        let write_half = KtlsStream {
+            inner: w,
+            write_closed: self.write_closed,
+            read: Mutex::new(ReadSide {
+                read_closed: true,
+                ..ReadSide::new(None)
+            }),
+            write: self.write,
+            stats: self.stats,
+            info: Default::default(),
+            extensions: Default::default(),
+            keying_material: Default::default(),
+        };
+        (read_half, write_half)
+    }
+
+    /// Borrows the I/O, the read-side state and the write-side state
+    /// separately, so they can be used concurrently
+    pub(crate) fn split_states(&mut self) -> (&mut IO, ReadState<'_>, WriteState<'_>) {
+        let read_state = get_mut(&mut self.read).state(&self.write_closed, &self.stats);
+        let write_state = get_mut(&mut self.write).state(&self.write_closed, &self.stats);
+        (&mut self.inner, read_state, write_state)
+    }
+
+    pub(crate) fn is_write_closed(&mut self) -> bool {
+        *self.write_closed.get_mut()
+    }
+
+    pub(crate) fn mark_read_closed(&mut self) {
+        get_mut(&mut self.read).read_closed = true;
+    }
+
+    pub(crate) fn mark_write_closed(&mut self) {
+        *self.write_closed.get_mut() = true;
+    }
+
+    /// In record mode, whether the last read is known to have ended on a
+    /// record boundary: the kernel stops a read short of the buffer's end only
+    /// once it runs out of whole records, a read that fills the buffer may
+    /// have cut one. `false` for the plaintext rustls had buffered before the
+    /// offload, which lost its record boundaries.
+    pub fn at_record_boundary(&self) -> bool {
+        lock(&self.read).at_record_boundary
+    }
+
+    /// Returns the counters for this stream, see [crate::stats::spawn_reporter]
+    /// to report them periodically.
+    pub fn stats(&self) -> &Arc<StreamStats> {
+        &self.stats
     }
 
     /// Returns a reference to the original I/O
@@ -54,6 +334,110 @@ where
     pub fn get_mut(&mut self) -> &mut IO {
         &mut self.inner
     }
+
+    /// Returns the local address of the underlying socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.with_sock_ref(|sock| to_socket_addr(sock.local_addr()?))
+    }
+
+    /// Returns the remote address of the underlying socket
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.with_sock_ref(|sock| to_socket_addr(sock.peer_addr()?))
+    }
+
+    /// Sets TCP_NODELAY on the underlying socket
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.with_sock_ref(|sock| sock.set_nodelay(nodelay))
+    }
+
+    /// Gets TCP_NODELAY on the underlying socket
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.with_sock_ref(|sock| sock.nodelay())
+    }
+
+    /// Sets IP_TTL on the underlying socket
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.with_sock_ref(|sock| sock.set_ttl(ttl))
+    }
+
+    /// Gets IP_TTL on the underlying socket
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.with_sock_ref(|sock| sock.ttl())
+    }
+
+    /// Gets and clears SO_ERROR on the underlying socket, which holds the
+    /// TCP-level cause (e.g. ETIMEDOUT) of a failed kTLS send
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        self.with_sock_ref(|sock| sock.take_error())
+    }
+
+    /// Returns RTT, retransmits, delivery rate etc. of the underlying TCP
+    /// connection, to correlate with TLS throughput
+    pub fn tcp_info(&self) -> io::Result<crate::TcpInfo> {
+        crate::ffi::get_tcp_info(self.inner.as_fd().as_raw_fd()).map(Into::into)
+    }
+
+    /// Reads back the TLS version and cipher the kernel has installed for
+    /// each direction, to check that the offload actually took effect
+    pub fn ktls_info(&self) -> io::Result<crate::KtlsInfo> {
+        crate::KtlsInfo::get(self.inner.as_fd().as_raw_fd())
+    }
+
+    /// How many bytes the kernel received but the application didn't read
+    /// yet (SIOCINQ). These are TLS records, so this includes their framing
+    /// and tags, and leaves out the drained plaintext.
+    pub fn bytes_unread(&self) -> io::Result<usize> {
+        crate::ffi::bytes_in_recv_queue(self.inner.as_fd().as_raw_fd())
+    }
+
+    /// How many bytes were written but not acknowledged by the peer yet
+    /// (SIOCOUTQ), TLS framing included
+    pub fn bytes_unsent(&self) -> io::Result<usize> {
+        crate::ffi::bytes_in_send_queue(self.inner.as_fd().as_raw_fd())
+    }
+
+    /// Stop reading from the peer while keeping the write side open, e.g. to
+    /// flush a response after deciding not to consume the rest of a request.
+    /// Subsequent reads return EOF; writes and shutdown work as usual.
+    pub fn shutdown_read(&mut self) -> io::Result<()> {
+        if get_mut(&mut self.read).read_closed {
+            return Ok(());
+        }
+
+        self.with_sock_ref(|sock| sock.shutdown(std::net::Shutdown::Read))?;
+        let read = get_mut(&mut self.read);
+        read.read_closed = true;
+        read.drained = None;
+        Ok(())
+    }
+
+    fn with_sock_ref<R>(&self, f: impl FnOnce(SockRef<'_>) -> R) -> R {
+        f(SockRef::from(&self.inner.as_fd()))
+    }
+}
+
+fn to_socket_addr(addr: socket2::SockAddr) -> io::Result<SocketAddr> {
+    addr.as_socket().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "socket address is not an IPv4/IPv6 address",
+        )
+    })
+}
+
+/// A context for the `try_*` methods, which report readiness through
+/// `WouldBlock` instead of waking the task
+fn noop_context() -> task::Context<'static> {
+    task::Context::from_waker(futures::task::noop_waker_ref())
+}
+
+/// Takes a unit of the task's tokio coop budget, yielding back to the
+/// executor once it's spent: the socket only takes from it when it's polled,
+/// not for reads served from the drain buffer nor the ones following a
+/// control message, so a hot connection could otherwise starve the other
+/// tasks on its worker thread
+fn poll_budget(cx: &mut task::Context<'_>) -> task::Poll<()> {
+    std::future::Future::poll(std::pin::pin!(tokio::task::consume_budget()), cx)
 }
 
 #[derive(Debug, PartialEq, Clone, Copy, num_enum::FromPrimitive)]
@@ -65,14 +449,6 @@ enum TlsAlertLevel {
     Other(u8),
 }
 
-#[derive(Debug, PartialEq, Clone, Copy, num_enum::FromPrimitive)]
-#[repr(u8)]
-enum TlsAlertDescription {
-    CloseNotify = 0,
-    #[num_enum(catch_all)]
-    Other(u8),
-}
-
 #[derive(Debug, PartialEq, Clone, Copy, num_enum::FromPrimitive)]
 #[repr(u8)]
 enum TlsRecordType {
@@ -84,364 +460,1398 @@ enum TlsRecordType {
     Other(u8),
 }
 
+/// The control message space for a `recvmsg`, reusing `buf`'s allocation:
+/// kTLS only ever sends back the record type
+fn cmsg_space(buf: &mut Vec<u8>) -> &mut Vec<u8> {
+    buf.clear();
+    buf.reserve(unsafe { libc::CMSG_SPACE(std::mem::size_of::<u8>() as _) as _ });
+    buf
+}
+
+/// The type of every handshake message in a record: a record may hold several
+/// (each a 1-byte type and a 24-bit length), e.g. a NewSessionTicket followed
+/// by a KeyUpdate
+fn handshake_types(mut handshake: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    std::iter::from_fn(move || {
+        let (&msg_type, rest) = handshake.split_first()?;
+        let len = match rest {
+            [a, b, c, ..] => u32::from_be_bytes([0, *a, *b, *c]) as usize,
+            // a header cut short, nothing follows it
+            _ => rest.len(),
+        };
+        handshake = rest.get(3 + len..).unwrap_or_default();
+        Some(msg_type)
+    })
+}
+
+/// The handshake message type of a TLS 1.3 KeyUpdate
+const KEY_UPDATE: u8 = 24;
+
+fn is_key_update(handshake: &[u8]) -> bool {
+    handshake_types(handshake).any(|t| t == KEY_UPDATE)
+}
+
+/// The handshake message type of a TLS 1.2 HelloRequest
+const HELLO_REQUEST: u8 = 0;
+
+fn is_hello_request(handshake: &[u8]) -> bool {
+    handshake_types(handshake).any(|t| t == HELLO_REQUEST)
+}
+
+/// The handshake message type of a CertificateRequest, only seen after the
+/// handshake when a TLS 1.3 server asks for post-handshake authentication
+const CERTIFICATE_REQUEST: u8 = 13;
+
+fn is_certificate_request(handshake: &[u8]) -> bool {
+    handshake_types(handshake).any(|t| t == CERTIFICATE_REQUEST)
+}
+
+/// A TLS 1.2 server asking to renegotiate can be told no with a warning, the
+/// connection goes on (RFC 5246, section 7.4.1.1)
+fn reject_renegotiation(fd: RawFd) -> io::Result<()> {
+    tracing::debug!("peer asked to renegotiate, refusing");
+    crate::ffi::send_alert(
+        fd,
+        crate::ffi::AlertLevel::Warning,
+        AlertDescription::NoRenegotiation,
+    )
+}
+
+/// Why the read side failed for good: every read after the one that ran
+/// into it fails the same way, rather than returning EOF or data decrypted
+/// with the wrong keys
+#[derive(Debug, Clone, Copy)]
+enum ReadFailure {
+    /// Deriving the next receive keys takes the current traffic secret, which
+    /// rustls doesn't hand over (only the keys derived from it), so a peer
+    /// that rekeys can't be followed
+    KeyUpdate,
+    /// The peer sent a fatal alert (or a warning with an unknown level)
+    Alert {
+        level: AlertLevel,
+        description: AlertDescription,
+    },
+}
+
+impl ReadFailure {
+    fn to_io_error(self) -> io::Error {
+        match self {
+            ReadFailure::KeyUpdate => io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the peer sent a TLS 1.3 KeyUpdate, rekeying offloaded connections isn't supported",
+            ),
+            ReadFailure::Alert { level, description } => io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                Error::Alert { level, description },
+            ),
+        }
+    }
+}
+
+/// Alerts with an unknown level are taken for fatal ones, and half an alert
+/// for a close_notify, see `poll_read_with`
+fn parse_alert(payload: &[u8]) -> (AlertLevel, AlertDescription) {
+    let level = match payload
+        .first()
+        .map(|&level| TlsAlertLevel::from_primitive(level))
+    {
+        Some(TlsAlertLevel::Warning) => AlertLevel::Warning,
+        _ => AlertLevel::Fatal,
+    };
+    let description = AlertDescription::from(payload.get(1).copied().unwrap_or(0));
+    (level, description)
+}
+
+/// What's reported to a [ControlRecordHandler], if anything: change_cipher_spec
+/// and application data never make it there
+fn control_record(record_type: TlsRecordType, payload: &[u8]) -> Option<ControlRecord> {
+    match record_type {
+        TlsRecordType::Alert => {
+            let (level, description) = parse_alert(payload);
+            Some(ControlRecord::Alert { level, description })
+        }
+        TlsRecordType::Handshake => Some(ControlRecord::Handshake(payload.to_vec())),
+        TlsRecordType::Other(record_type) => Some(ControlRecord::Other {
+            record_type,
+            payload: payload.to_vec(),
+        }),
+        TlsRecordType::ChangeCipherSpec | TlsRecordType::ApplicationData => None,
+    }
+}
+
+/// How many reads a single `poll_read` may chain to fill the caller's buffer
+const GREEDY_READ_ROUNDS: usize = 16;
+
+/// After a successful read, keep reading while the caller's buffer has room
+/// and data is immediately available: kTLS stops a read at records that
+/// aren't decrypted yet, or at a control message, which makes `read_exact` on
+/// large buffers needlessly slow. Bounded by
+/// [GREEDY_READ_ROUNDS] so a single connection can't hog the worker thread.
+fn greedy_read<IO>(
+    mut inner: Pin<&mut IO>,
+    cx: &mut task::Context<'_>,
+    buf: &mut ReadBuf<'_>,
+    filled_before: usize,
+    pending_read_error: &mut Option<io::Error>,
+) where
+    IO: AsyncRead,
+{
+    let mut last_filled = filled_before;
+    for _ in 1..GREEDY_READ_ROUNDS {
+        let filled = buf.filled().len();
+        if filled == last_filled || buf.remaining() == 0 {
+            // EOF, error on the first read, or buffer full
+            return;
+        }
+        last_filled = filled;
+
+        match inner.as_mut().poll_read(cx, buf) {
+            task::Poll::Ready(Ok(())) => {}
+            // nothing more for now
+            task::Poll::Pending => return,
+            // a control message: it stays in the socket until it's handled
+            // by the next read
+            task::Poll::Ready(Err(e)) if e.raw_os_error() == Some(5) => return,
+            task::Poll::Ready(Err(e)) => {
+                // deliver what we have first
+                *pending_read_error = Some(e);
+                return;
+            }
+        }
+    }
+}
+
 impl<IO> AsyncRead for KtlsStream<IO>
 where
-    IO: AsRawFd + AsyncRead + AsyncReadReady,
+    IO: AsFd + AsyncRead + AsyncReadReady,
 {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut task::Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> task::Poll<io::Result<()>> {
-        tracing::trace!(buf.remaining = %buf.remaining(), "KtlsStream::poll_read");
+        let fd = self.inner.as_fd().as_raw_fd();
+        let this = self.project();
+        let mut state = get_mut(this.read).state(this.write_closed, this.stats);
+        poll_read_with(this.inner, fd, &mut state, cx, buf)
+    }
+}
+
+/// What reading from a [KtlsStream] keeps track of
+struct ReadSide {
+    read_closed: bool,
+    read_failure: Option<ReadFailure>,
+    drained: Option<(usize, Vec<u8>)>,
+    // error hit by a greedy read after some data was already read, to be
+    // returned by the next read
+    pending_read_error: Option<io::Error>,
+    record_reads: bool,
+    at_record_boundary: bool,
+    control_record_handler: Option<ControlRecordHandler>,
+    // control message space for `recvmsg`, allocated on the first control
+    // message, then reused
+    cmsg_buffer: Vec<u8>,
+    // where control records are received, same
+    record_buffer: Vec<u8>,
+}
+
+impl ReadSide {
+    fn new(drained: Option<Vec<u8>>) -> Self {
+        Self {
+            read_closed: false,
+            read_failure: None,
+            drained: drained.map(|drained| (0, drained)),
+            pending_read_error: None,
+            record_reads: false,
+            at_record_boundary: true,
+            control_record_handler: None,
+            cmsg_buffer: Vec::new(),
+            record_buffer: Vec::new(),
+        }
+    }
 
-        if self.read_closed {
-            return task::Poll::Ready(Ok(()));
+    fn state<'a>(
+        &'a mut self,
+        write_closed: &'a AtomicBool,
+        stats: &'a StreamStats,
+    ) -> ReadState<'a> {
+        ReadState {
+            read_closed: &mut self.read_closed,
+            read_failure: &mut self.read_failure,
+            write_closed,
+            drained: &mut self.drained,
+            pending_read_error: &mut self.pending_read_error,
+            record_reads: self.record_reads,
+            at_record_boundary: &mut self.at_record_boundary,
+            control_record_handler: self.control_record_handler.as_ref(),
+            cmsg_buffer: &mut self.cmsg_buffer,
+            record_buffer: &mut self.record_buffer,
+            stats,
         }
+    }
+}
 
-        if buf.remaining() == 0 {
-            return task::Poll::Ready(Ok(()));
+/// What writing to a [KtlsStream] keeps track of
+struct WriteSide {
+    atomic_writes: bool,
+    coalesce_writes: bool,
+    max_record_size: Option<usize>,
+    shutdown_mode: ShutdownMode,
+    // in atomic write and coalescing modes, the part of accepted writes the
+    // socket didn't take yet
+    write_backlog: Option<(usize, Vec<u8>)>,
+}
+
+impl WriteSide {
+    fn new() -> Self {
+        Self {
+            atomic_writes: false,
+            coalesce_writes: false,
+            max_record_size: None,
+            shutdown_mode: ShutdownMode::default(),
+            write_backlog: None,
         }
+    }
 
-        let mut this = self.project();
+    fn state<'a>(
+        &'a mut self,
+        write_closed: &'a AtomicBool,
+        stats: &'a StreamStats,
+    ) -> WriteState<'a> {
+        WriteState {
+            write_closed,
+            atomic_writes: self.atomic_writes,
+            coalesce_writes: self.coalesce_writes,
+            max_record_size: self.max_record_size,
+            shutdown_mode: self.shutdown_mode,
+            write_backlog: &mut self.write_backlog,
+            stats,
+        }
+    }
+}
 
-        if let Some((drain_index, drained)) = this.drained.as_mut() {
-            let drained = &drained[*drain_index..];
-            let len = std::cmp::min(buf.remaining(), drained.len());
+fn get_mut<T>(mutex: &mut Mutex<T>) -> &mut T {
+    mutex.get_mut().unwrap_or_else(PoisonError::into_inner)
+}
 
-            tracing::trace!(%len, "KtlsStream::poll_read, can take from drain");
-            buf.put_slice(&drained[..len]);
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
-            *drain_index += len;
-            if *drain_index >= drained.len() {
-                tracing::trace!("KtlsStream::poll_read, done draining");
-                *this.drained = None;
+fn into_inner<T>(mutex: Mutex<T>) -> T {
+    mutex.into_inner().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The read-side state of a stream, borrowed so that [KtlsStream] and its
+/// borrowed read half go through the same code
+pub(crate) struct ReadState<'a> {
+    read_closed: &'a mut bool,
+    read_failure: &'a mut Option<ReadFailure>,
+    // set when an alert is received, since we reply with a close_notify
+    write_closed: &'a AtomicBool,
+    drained: &'a mut Option<(usize, Vec<u8>)>,
+    pending_read_error: &'a mut Option<io::Error>,
+    record_reads: bool,
+    at_record_boundary: &'a mut bool,
+    control_record_handler: Option<&'a ControlRecordHandler>,
+    cmsg_buffer: &'a mut Vec<u8>,
+    record_buffer: &'a mut Vec<u8>,
+    stats: &'a StreamStats,
+}
+
+pub(crate) fn poll_read_with<IO>(
+    mut inner: Pin<&mut IO>,
+    fd: RawFd,
+    state: &mut ReadState<'_>,
+    cx: &mut task::Context<'_>,
+    buf: &mut ReadBuf<'_>,
+) -> task::Poll<io::Result<()>>
+where
+    IO: AsyncRead,
+{
+    tracing::trace!(buf.remaining = %buf.remaining(), "KtlsStream::poll_read");
+
+    if buf.remaining() == 0 {
+        return task::Poll::Ready(Ok(()));
+    }
+
+    if let Some(e) = state.pending_read_error.take() {
+        return task::Poll::Ready(Err(e));
+    }
+    futures::ready!(poll_budget(cx));
+
+    if let Some((drain_index, drained)) = state.drained.as_mut() {
+        let drained = &drained[*drain_index..];
+        let len = std::cmp::min(buf.remaining(), drained.len());
+
+        tracing::trace!(%len, "KtlsStream::poll_read, can take from drain");
+        buf.put_slice(&drained[..len]);
+        state.stats.record_read(len);
+
+        *drain_index += len;
+        *state.at_record_boundary = false;
+        // `drained` only holds what's left, compare against that
+        if len == drained.len() {
+            tracing::trace!("KtlsStream::poll_read, done draining");
+            *state.drained = None;
+        }
+        cx.waker().wake_by_ref();
+
+        tracing::trace!("KtlsStream::poll_read, returning after drain");
+        return task::Poll::Ready(Ok(()));
+    }
+
+    // checked after the drained data, which comes before a close_notify the
+    // peer sent while we were draining
+    if let Some(res) = read_side_done(state) {
+        return task::Poll::Ready(res);
+    }
+
+    let filled_before = buf.filled().len();
+    let read_res = inner.as_mut().poll_read(cx, buf);
+    if read_res.is_ready() && !state.record_reads {
+        greedy_read(
+            inner.as_mut(),
+            cx,
+            buf,
+            filled_before,
+            state.pending_read_error,
+        );
+    }
+    if let task::Poll::Ready(Err(e)) = &read_res {
+        // 5 is a generic "input/output error", it happens when
+        // using poll_read on a kTLS socket that just received
+        // a control message
+        if let Some(5) = e.raw_os_error() {
+            return poll_control_record(fd, state, cx);
+        }
+    }
+
+    if let task::Poll::Ready(Ok(())) = &read_res {
+        state.stats.record_read(buf.filled().len() - filled_before);
+        // the kernel goes on with the next record while the buffer has
+        // room, so a read that didn't fill it ended with a whole record
+        *state.at_record_boundary = buf.remaining() > 0;
+    }
+    read_res
+}
+
+/// What a read returns without touching the socket once the read side is
+/// done: the failure that ended it, or EOF
+fn read_side_done(state: &ReadState<'_>) -> Option<io::Result<()>> {
+    if let Some(failure) = *state.read_failure {
+        return Some(Err(failure.to_io_error()));
+    }
+    if *state.read_closed {
+        return Some(Ok(()));
+    }
+    None
+}
+
+/// What a peek returns for the error the next read will: the same, without
+/// taking it
+fn peek_error(e: &io::Error) -> io::Error {
+    match e.raw_os_error() {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => io::Error::new(e.kind(), e.to_string()),
+    }
+}
+
+/// Handles the control record a read ran into (it failed with EIO): ready
+/// with `Ok` on EOF, otherwise pending, with the task woken to read on
+fn poll_control_record(
+    fd: RawFd,
+    state: &mut ReadState<'_>,
+    cx: &mut task::Context<'_>,
+) -> task::Poll<io::Result<()>> {
+    if let ControlOutcome::Eof = recv_control_record(fd, state)? {
+        return task::Poll::Ready(Ok(()));
+    }
+
+    // FIXME: this is hacky, but can we do better?
+    // after we handled (..ignored) the control message, we don't
+    // know whether the socket is still ready to be read or not.
+    //
+    // we could try looping (tricky code structure), but we can't,
+    // for example, just call `poll_read`, which might fail not
+    // not with EAGAIN/EWOULDBLOCK, but because _another_ control
+    // message is available.
+    cx.waker().wake_by_ref();
+    task::Poll::Pending
+}
+
+/// The most plaintext a TLS record can hold
+const MAX_RECORD_LEN: usize = 16 * 1024;
+
+/// What a control record means for the read that ran into it
+enum ControlOutcome {
+    /// Nothing for the reader, read on
+    Continue,
+    /// The peer sent a close_notify
+    Eof,
+}
+
+/// Receives the control record a read ran into (kTLS fails reads with EIO
+/// until it's received along with its record type) and acts on it. It goes
+/// to a buffer of its own, big enough for any record, so neither the
+/// [ControlRecordHandler] nor the checks below see a truncated one.
+fn recv_control_record(fd: RawFd, state: &mut ReadState<'_>) -> io::Result<ControlOutcome> {
+    if state.record_buffer.len() < MAX_RECORD_LEN {
+        state.record_buffer.resize(MAX_RECORD_LEN, 0);
+    }
+    let mut iov = [IoSliceMut::new(&mut state.record_buffer[..])];
+    let cmsgspace = cmsg_space(state.cmsg_buffer);
+
+    let r = match recvmsg::<SockaddrIn>(fd, &mut iov, Some(cmsgspace), MsgFlags::empty()) {
+        Ok(r) => r,
+        // another reader of the socket (see `try_clone`) took it
+        Err(Errno::EAGAIN) => return Ok(ControlOutcome::Continue),
+        Err(e) => {
+            // ok I guess it really failed then
+            tracing::trace!(?e, "recvmsg failed");
+            return Err(e.into());
+        }
+    };
+    let cmsg = r
+        .cmsgs()
+        .next()
+        .expect("we should've received exactly one control message");
+
+    let record_type = match cmsg {
+        ControlMessageOwned::TlsGetRecordType(t) => t,
+        _ => panic!("unexpected cmsg type: {cmsg:#?}"),
+    };
+    state.stats.record_control();
+
+    let record_type = TlsRecordType::from_primitive(record_type);
+    let payload = r.iovs().next().unwrap_or_default();
+    if let Some(handler) = state.control_record_handler {
+        if let Some(record) = control_record(record_type, payload) {
+            handler(record);
+        }
+    }
+
+    match record_type {
+        TlsRecordType::ChangeCipherSpec => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "received a change_cipher_spec record after the handshake",
+        )),
+        TlsRecordType::Alert => {
+            // https://github.com/facebookincubator/fizz/blob/fff6d9d49d3c554ab66b58822d1e1fe93e8d80f2/fizz/experimental/ktls/AsyncKTLSSocket.cpp#L144
+            let (level, description) = parse_alert(payload);
+
+            // https://datatracker.ietf.org/doc/html/rfc5246#section-7.2
+            // alerts we should handle are ones with fatal level or a
+            // close_notify
+            if level == AlertLevel::Warning && description != AlertDescription::CloseNotify {
+                // a warning (e.g. user_canceled or no_renegotiation): the
+                // session goes on, returning here would look like EOF. The
+                // control record handler gets to see it, if any.
+                tracing::debug!(?level, ?description, "ignoring TLS warning alert");
+                return Ok(ControlOutcome::Continue);
+            }
+
+            tracing::trace!(?level, ?description, "got TLS alert");
+            *state.read_closed = true;
+            // if we half-closed the stream already, our close_notify was sent
+            // then, and the socket doesn't take writes anymore
+            if !state.write_closed.swap(true, Ordering::Relaxed) {
+                crate::ffi::send_close_notify(fd)?;
+            }
+            // the file descriptor will be closed when the stream is dropped,
+            // we already protect against writes-after-close_notify through
+            // the write_closed flag
+            if description == AlertDescription::CloseNotify {
+                return Ok(ControlOutcome::Eof);
+            }
+            let failure = ReadFailure::Alert { level, description };
+            *state.read_failure = Some(failure);
+            Err(failure.to_io_error())
+        }
+        TlsRecordType::Handshake => {
+            if is_key_update(payload) {
+                // every record after this one is encrypted with keys we
+                // can't derive, fail now rather than on garbage
+                *state.read_failure = Some(ReadFailure::KeyUpdate);
+                return Err(ReadFailure::KeyUpdate.to_io_error());
+            }
+            if is_certificate_request(payload) {
+                // the stream stays usable, if the server lets it
+                let err = Error::PostHandshakeAuthRequested;
+                return Err(io::Error::new(io::ErrorKind::Unsupported, err));
             }
-            cx.waker().wake_by_ref();
-
-            tracing::trace!("KtlsStream::poll_read, returning after drain");
-            return task::Poll::Ready(Ok(()));
-        }
-
-        let read_res = this.inner.as_mut().poll_read(cx, buf);
-        if let task::Poll::Ready(Err(e)) = &read_res {
-            // 5 is a generic "input/output error", it happens when
-            // using poll_read on a kTLS socket that just received
-            // a control message
-            if let Some(5) = e.raw_os_error() {
-                // could be a control message, let's check
-                let fd = this.inner.as_raw_fd();
-
-                // XXX: recvmsg wants a `&mut Vec<u8>` so it's able to resize it
-                // I guess? Or so there's a clear separation between uninitialized
-                // and initialized? We could probably get read of that heap alloc, idk.
-
-                // let mut cmsgspace =
-                //     [0u8; unsafe { libc::CMSG_SPACE(std::mem::size_of::<u8>() as _) as _ }];
-                let mut cmsgspace = Vec::with_capacity(unsafe {
-                    libc::CMSG_SPACE(std::mem::size_of::<u8>() as _) as _
-                });
-
-                let mut iov = [IoSliceMut::new(buf.initialize_unfilled())];
-                let flags = MsgFlags::empty();
-
-                let r = recvmsg::<SockaddrIn>(fd, &mut iov, Some(&mut cmsgspace), flags);
-                let r = match r {
-                    Ok(r) => r,
-                    Err(Errno::EAGAIN) => {
-                        unreachable!("expected a control message, got EAGAIN")
-                    }
-                    Err(e) => {
-                        // ok I guess it really failed then
-                        tracing::trace!(?e, "recvmsg failed");
-                        return Err(e.into()).into();
-                    }
-                };
-                let cmsg = r
-                    .cmsgs()
-                    .next()
-                    .expect("we should've received exactly one control message");
-
-                let record_type = match cmsg {
-                    ControlMessageOwned::TlsGetRecordType(t) => t,
-                    _ => panic!("unexpected cmsg type: {cmsg:#?}"),
-                };
-
-                match TlsRecordType::from_primitive(record_type) {
-                    TlsRecordType::ChangeCipherSpec => {
-                        panic!("change_cipher_spec isn't supported by the ktls crate")
-                    }
-                    TlsRecordType::Alert => {
-                        // the alert level and description are in iovs
-                        let iov = r.iovs().next().expect("expected data in iovs");
-
-                        let (level, description) = match iov {
-                            [] => {
-                                // we have an early return case for that
-                                unreachable!();
-                            }
-                            &[level] => {
-                                // https://github.com/facebookincubator/fizz/blob/fff6d9d49d3c554ab66b58822d1e1fe93e8d80f2/fizz/experimental/ktls/AsyncKTLSSocket.cpp#L144
-                                //
-                                // Since all alerts (even warning-level alerts)
-                                // signal the abort of a TLS session, we do not
-                                // need to worry about additional application
-                                // data.
-                                //
-                                // If we only have half the alert (because the
-                                // user passed a buffer of size 1), just assume
-                                // it's a close_notify
-                                (
-                                    TlsAlertLevel::from_primitive(level),
-                                    TlsAlertDescription::CloseNotify,
-                                )
-                            }
-                            &[level, description] => (
-                                TlsAlertLevel::from_primitive(level),
-                                TlsAlertDescription::from_primitive(description),
-                            ),
-                            _ => {
-                                unreachable!(
-                                    "TLS alerts are exactly 2 bytes, your kTLS is misbehaving"
-                                );
-                            }
-                        };
-
-                        match (level, description) {
-                            // https://datatracker.ietf.org/doc/html/rfc5246#section-7.2
-                            // alerts we should handle are ones with fatal level or a
-                            // close_notify
-                            (_, TlsAlertDescription::CloseNotify) | (TlsAlertLevel::Fatal, _) => {
-                                tracing::trace!(?level, ?description, "got TLS alert");
-                                *this.read_closed = true;
-                                *this.write_closed = true;
-                                if let Err(e) =
-                                    crate::ffi::send_close_notify(this.inner.as_raw_fd())
-                                {
-                                    return Err(e).into();
-                                }
-                                // the file descriptor will be closed when the stream is dropped,
-                                // we already protect against writes-after-close_notify through
-                                // the write_closed flag
-                                return task::Poll::Ready(Ok(()));
-                            }
-                            _ => {
-                                // we got something we probably can't handle
-                            }
-                        }
-                        return task::Poll::Ready(Ok(()));
-                    }
-                    TlsRecordType::Handshake => {
-                        // TODO: this is where we receive TLS 1.3 resumption tickets,
-                        // should those be stored anywhere? I'm not even sure what
-                        // format they have at this point
-                        tracing::trace!(
-                            "ignoring handshake message (probably a resumption ticket)"
-                        );
-                    }
-                    TlsRecordType::ApplicationData => {
-                        unreachable!("received TLS application in recvmsg, this is supposed to happen in the poll_read codepath")
-                    }
-                    TlsRecordType::Other(t) => {
-                        // just ignore the record?
-                        tracing::trace!("received record_type {t:#?}");
-                    }
-                };
-
-                // FIXME: this is hacky, but can we do better?
-                // after we handled (..ignored) the control message, we don't
-                // know whether the socket is still ready to be read or not.
-                //
-                // we could try looping (tricky code structure), but we can't,
-                // for example, just call `poll_read`, which might fail not
-                // not with EAGAIN/EWOULDBLOCK, but because _another_ control
-                // message is available.
-                cx.waker().wake_by_ref();
-                return task::Poll::Pending;
+            if is_hello_request(payload) && !state.write_closed.load(Ordering::Relaxed) {
+                reject_renegotiation(fd)?;
             }
+            // TODO: this is where we receive TLS 1.3 resumption tickets,
+            // should those be stored anywhere? I'm not even sure what
+            // format they have at this point
+            tracing::trace!("ignoring handshake message (probably a resumption ticket)");
+            Ok(ControlOutcome::Continue)
         }
+        TlsRecordType::ApplicationData => {
+            unreachable!("received TLS application in recvmsg, this is supposed to happen in the poll_read codepath")
+        }
+        TlsRecordType::Other(t) => {
+            // just ignore the record?
+            tracing::trace!("received record_type {t:#?}");
+            Ok(ControlOutcome::Continue)
+        }
+    }
+}
 
-        read_res
+impl<IO> KtlsStream<IO>
+where
+    IO: AsFd + AsyncWrite,
+{
+    /// Sends a `close_notify` alert, after whatever atomic or coalesced writes
+    /// are still buffered, but leaves the TCP connection open: no FIN is sent
+    /// until the stream is shut down or dropped. Writes fail afterwards, reads
+    /// keep working. Does nothing if a `close_notify` was already sent.
+    pub fn poll_close_notify(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        let fd = self.inner.as_fd().as_raw_fd();
+        let this = self.project();
+        let mut state = get_mut(this.write).state(this.write_closed, this.stats);
+        poll_close_notify_with(this.inner, fd, &mut state, cx)
+    }
+
+    /// See [KtlsStream::poll_close_notify]
+    pub async fn close_notify(&mut self) -> io::Result<()>
+    where
+        IO: Unpin,
+    {
+        futures::future::poll_fn(|cx| Pin::new(&mut *self).poll_close_notify(cx)).await
+    }
+
+    /// Sends a TLS alert, after whatever atomic or coalesced writes are still
+    /// buffered, e.g. `user_canceled` or `internal_error` before closing the
+    /// connection. Nothing can be written after a fatal alert or a
+    /// `close_notify`, use [KtlsStream::close_notify] for the latter.
+    pub fn poll_send_alert(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        level: AlertLevel,
+        description: AlertDescription,
+    ) -> task::Poll<io::Result<()>> {
+        let fd = self.inner.as_fd().as_raw_fd();
+        let this = self.project();
+        let mut state = get_mut(this.write).state(this.write_closed, this.stats);
+        poll_send_alert_with(this.inner, fd, &mut state, cx, level, description)
+    }
+
+    /// See [KtlsStream::poll_send_alert]
+    pub async fn send_alert(
+        &mut self,
+        level: AlertLevel,
+        description: AlertDescription,
+    ) -> io::Result<()>
+    where
+        IO: Unpin,
+    {
+        futures::future::poll_fn(|cx| Pin::new(&mut *self).poll_send_alert(cx, level, description))
+            .await
+    }
+
+    /// Sends a `close_notify` from a background task if the returned stream is
+    /// dropped before having been shut down, e.g. when a handler panics or
+    /// returns early. Outside of a tokio runtime, the alert is sent right
+    /// away, and lost if the socket can't take it.
+    pub fn close_notify_on_drop(self) -> CloseNotifyOnDrop<IO>
+    where
+        IO: Unpin + Send + 'static,
+    {
+        CloseNotifyOnDrop::new(self)
     }
 }
 
 impl<IO> AsyncWrite for KtlsStream<IO>
 where
-    IO: AsRawFd + AsyncWrite,
+    IO: AsFd + AsyncWrite,
 {
     fn poll_write(
         self: Pin<&mut Self>,
         cx: &mut task::Context<'_>,
         buf: &[u8],
     ) -> task::Poll<io::Result<usize>> {
-        if self.write_closed {
-            return task::Poll::Ready(Ok(0));
-        }
+        let this = self.project();
+        let mut state = get_mut(this.write).state(this.write_closed, this.stats);
+        poll_write_with(this.inner, &mut state, cx, buf)
+    }
 
-        self.project().inner.poll_write(cx, buf)
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> task::Poll<io::Result<usize>> {
+        let this = self.project();
+        let mut state = get_mut(this.write).state(this.write_closed, this.stats);
+        poll_write_vectored_with(this.inner, &mut state, cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<io::Result<()>> {
-        self.project().inner.poll_flush(cx)
+        let this = self.project();
+        let mut state = get_mut(this.write).state(this.write_closed, this.stats);
+        poll_flush_with(this.inner, &mut state, cx)
     }
 
+    /// Only closes the write direction (see [ShutdownMode] for what's sent),
+    /// the stream can still be read from until the peer closes its side.
     fn poll_shutdown(
         self: Pin<&mut Self>,
         cx: &mut task::Context<'_>,
     ) -> task::Poll<io::Result<()>> {
+        let fd = self.inner.as_fd().as_raw_fd();
         let this = self.project();
+        let mut state = get_mut(this.write).state(this.write_closed, this.stats);
+        poll_shutdown_with(this.inner, fd, &mut state, cx)
+    }
+}
+
+/// The level of an alert sent with [KtlsStream::send_alert]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlertLevel {
+    /// The connection can go on, although most implementations close it
+    /// anyway
+    Warning,
+    /// The connection is closed right away, by both sides
+    Fatal,
+}
+
+/// What [KtlsStream]'s `poll_shutdown` sends to the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShutdownMode {
+    /// A `close_notify` alert, then a FIN
+    #[default]
+    CloseNotifyAndFin,
+    /// Only a FIN, for peers that don't cope with `close_notify`. They can't
+    /// tell a clean closure from a truncation attack then.
+    FinOnly,
+    /// Only a `close_notify` alert, the TCP connection stays open until the
+    /// stream is dropped, see [KtlsStream::close_notify]
+    CloseNotifyOnly,
+}
+
+/// The write-side state of a stream, see [ReadState]
+pub(crate) struct WriteState<'a> {
+    write_closed: &'a AtomicBool,
+    atomic_writes: bool,
+    coalesce_writes: bool,
+    max_record_size: Option<usize>,
+    shutdown_mode: ShutdownMode,
+    write_backlog: &'a mut Option<(usize, Vec<u8>)>,
+    stats: &'a StreamStats,
+}
+
+impl WriteState<'_> {
+    /// Only hand the kernel `max_record_size` bytes at a time, since it
+    /// starts a new record for every write
+    fn cap<'b>(&self, buf: &'b [u8]) -> &'b [u8] {
+        match self.max_record_size {
+            Some(max) if buf.len() > max => &buf[..max],
+            _ => buf,
+        }
+    }
+
+    fn cap_vectored<'b>(&self, bufs: &'b [IoSlice<'b>]) -> SmallVec<[IoSlice<'b>; 8]> {
+        let Some(mut remaining) = self.max_record_size else {
+            return bufs.iter().map(|buf| IoSlice::new(buf)).collect();
+        };
+
+        let mut capped = SmallVec::new();
+        for buf in bufs {
+            if remaining == 0 {
+                break;
+            }
+            let len = std::cmp::min(buf.len(), remaining);
+            capped.push(IoSlice::new(&buf[..len]));
+            remaining -= len;
+        }
+        capped
+    }
+}
+
+pub(crate) fn poll_write_with<IO>(
+    mut inner: Pin<&mut IO>,
+    state: &mut WriteState<'_>,
+    cx: &mut task::Context<'_>,
+    buf: &[u8],
+) -> task::Poll<io::Result<usize>>
+where
+    IO: AsyncWrite,
+{
+    if state.write_closed.load(Ordering::Relaxed) {
+        return task::Poll::Ready(Ok(0));
+    }
+
+    futures::ready!(poll_budget(cx));
+
+    if state.coalesce_writes {
+        poll_write_coalesced(inner.as_mut(), cx, buf, state)
+    } else if state.atomic_writes {
+        poll_write_atomic(inner.as_mut(), cx, buf, state)
+    } else {
+        let buf = state.cap(buf);
+        let res = inner.poll_write(cx, buf);
+        if let task::Poll::Ready(Ok(n)) = &res {
+            state.stats.record_write(*n);
+        }
+        res
+    }
+}
 
-        if !*this.write_closed {
+/// Writes all of `bufs` with a single syscall (if `IO` supports it), which the
+/// kernel packs in as few TLS records as possible
+pub(crate) fn poll_write_vectored_with<IO>(
+    mut inner: Pin<&mut IO>,
+    state: &mut WriteState<'_>,
+    cx: &mut task::Context<'_>,
+    bufs: &[IoSlice<'_>],
+) -> task::Poll<io::Result<usize>>
+where
+    IO: AsyncWrite,
+{
+    if state.write_closed.load(Ordering::Relaxed) {
+        return task::Poll::Ready(Ok(0));
+    }
+
+    futures::ready!(poll_budget(cx));
+
+    // the coalescing buffer must go out first, and the rest of a partial write
+    // must be kept so the order doesn't change
+    if state.atomic_writes || state.coalesce_writes {
+        poll_write_vectored_atomic(inner.as_mut(), cx, bufs, state)
+    } else {
+        let bufs = state.cap_vectored(bufs);
+        let res = inner.poll_write_vectored(cx, &bufs);
+        if let task::Poll::Ready(Ok(n)) = &res {
+            state.stats.record_write(*n);
+        }
+        res
+    }
+}
+
+pub(crate) fn poll_flush_with<IO>(
+    mut inner: Pin<&mut IO>,
+    state: &mut WriteState<'_>,
+    cx: &mut task::Context<'_>,
+) -> task::Poll<io::Result<()>>
+where
+    IO: AsyncWrite,
+{
+    futures::ready!(poll_write_backlog(inner.as_mut(), cx, state))?;
+    inner.poll_flush(cx)
+}
+
+pub(crate) fn poll_shutdown_with<IO>(
+    mut inner: Pin<&mut IO>,
+    fd: RawFd,
+    state: &mut WriteState<'_>,
+    cx: &mut task::Context<'_>,
+) -> task::Poll<io::Result<()>>
+where
+    IO: AsyncWrite,
+{
+    match state.shutdown_mode {
+        ShutdownMode::CloseNotifyAndFin | ShutdownMode::CloseNotifyOnly => {
             // they didn't hang up on us, we're nicely being asked to shut down,
             // let's send a close_notify (and not wait for them to send it back)
-            *this.write_closed = true;
-            if let Err(e) = crate::ffi::send_close_notify(this.inner.as_raw_fd()) {
-                return Err(e).into();
+            futures::ready!(poll_close_notify_with(inner.as_mut(), fd, state, cx))?;
+        }
+        ShutdownMode::FinOnly => {
+            if !state.write_closed.load(Ordering::Relaxed) {
+                futures::ready!(poll_write_backlog(inner.as_mut(), cx, state))?;
+                state.write_closed.store(true, Ordering::Relaxed);
             }
         }
+    }
+
+    if state.shutdown_mode == ShutdownMode::CloseNotifyOnly {
+        return inner.poll_flush(cx);
+    }
+
+    // this ends up closing the inner file descriptor no matter what
+    inner.poll_shutdown(cx)
+}
+
+pub(crate) fn poll_close_notify_with<IO>(
+    inner: Pin<&mut IO>,
+    fd: RawFd,
+    state: &mut WriteState<'_>,
+    cx: &mut task::Context<'_>,
+) -> task::Poll<io::Result<()>>
+where
+    IO: AsyncWrite,
+{
+    if !state.write_closed.load(Ordering::Relaxed) {
+        // whatever was accepted has to go out before the close_notify
+        futures::ready!(poll_write_backlog(inner, cx, state))?;
+
+        state.write_closed.store(true, Ordering::Relaxed);
+        if let Err(e) = crate::ffi::send_close_notify(fd) {
+            return Err(e).into();
+        }
+    }
+    task::Poll::Ready(Ok(()))
+}
+
+pub(crate) fn poll_send_alert_with<IO>(
+    inner: Pin<&mut IO>,
+    fd: RawFd,
+    state: &mut WriteState<'_>,
+    cx: &mut task::Context<'_>,
+    level: AlertLevel,
+    description: AlertDescription,
+) -> task::Poll<io::Result<()>>
+where
+    IO: AsyncWrite,
+{
+    if state.write_closed.load(Ordering::Relaxed) {
+        return Err(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "can't send an alert after close_notify",
+        ))
+        .into();
+    }
+    futures::ready!(poll_write_backlog(inner, cx, state))?;
+
+    if level == AlertLevel::Fatal || description == AlertDescription::CloseNotify {
+        state.write_closed.store(true, Ordering::Relaxed);
+    }
+    let ffi_level = match level {
+        AlertLevel::Warning => crate::ffi::AlertLevel::Warning,
+        AlertLevel::Fatal => crate::ffi::AlertLevel::Fatal,
+    };
+    crate::ffi::send_alert(fd, ffi_level, description).into()
+}
+
+/// The maximum amount of plaintext in a TLS record
+const COALESCE_LIMIT: usize = 16 * 1024;
+
+/// Buffer `buf` if it fits in the current record, see
+/// [KtlsStream::with_coalesced_writes]
+fn poll_write_coalesced<IO>(
+    mut inner: Pin<&mut IO>,
+    cx: &mut task::Context<'_>,
+    buf: &[u8],
+    state: &mut WriteState<'_>,
+) -> task::Poll<io::Result<usize>>
+where
+    IO: AsyncWrite,
+{
+    let buffered = state
+        .write_backlog
+        .as_ref()
+        .map_or(0, |(index, backlog)| backlog.len() - index);
+    let limit = state.max_record_size.unwrap_or(COALESCE_LIMIT);
+    if buffered + buf.len() > limit {
+        futures::ready!(poll_write_backlog(inner.as_mut(), cx, state))?;
+
+        if buf.len() >= limit {
+            // big enough to be sent as is
+            if state.atomic_writes {
+                return poll_write_atomic(inner, cx, buf, state);
+            }
+            let n = futures::ready!(inner.poll_write(cx, state.cap(buf)))?;
+            state.stats.record_write(n);
+            return task::Poll::Ready(Ok(n));
+        }
+    }
+
+    let (index, backlog) = state
+        .write_backlog
+        .get_or_insert_with(|| (0, Vec::with_capacity(limit)));
+    backlog.drain(..*index);
+    *index = 0;
+    backlog.extend_from_slice(buf);
+    task::Poll::Ready(Ok(buf.len()))
+}
+
+/// Write `buf` as a whole, see [KtlsStream::with_atomic_writes]
+fn poll_write_atomic<IO>(
+    mut inner: Pin<&mut IO>,
+    cx: &mut task::Context<'_>,
+    buf: &[u8],
+    state: &mut WriteState<'_>,
+) -> task::Poll<io::Result<usize>>
+where
+    IO: AsyncWrite,
+{
+    futures::ready!(poll_write_backlog(inner.as_mut(), cx, state))?;
+
+    let n = futures::ready!(inner.poll_write(cx, state.cap(buf)))?;
+    state.stats.record_write(n);
+    if n > 0 && n < buf.len() {
+        tracing::trace!(written = %n, len = %buf.len(), "partial atomic write, keeping the rest");
+        *state.write_backlog = Some((0, buf[n..].to_vec()));
+        return task::Poll::Ready(Ok(buf.len()));
+    }
+    task::Poll::Ready(Ok(n))
+}
+
+/// Write `bufs` as a whole, see [KtlsStream::with_atomic_writes]
+fn poll_write_vectored_atomic<IO>(
+    mut inner: Pin<&mut IO>,
+    cx: &mut task::Context<'_>,
+    bufs: &[IoSlice<'_>],
+    state: &mut WriteState<'_>,
+) -> task::Poll<io::Result<usize>>
+where
+    IO: AsyncWrite,
+{
+    futures::ready!(poll_write_backlog(inner.as_mut(), cx, state))?;
+
+    // only the syscall is capped, what's past the cap goes to the backlog
+    let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+    let n = futures::ready!(inner.poll_write_vectored(cx, &state.cap_vectored(bufs)))?;
+    state.stats.record_write(n);
+    if n > 0 && n < len {
+        tracing::trace!(written = %n, %len, "partial atomic write, keeping the rest");
+        let rest = bufs.iter().flat_map(|buf| buf.iter()).skip(n).copied();
+        *state.write_backlog = Some((0, rest.collect()));
+        return task::Poll::Ready(Ok(len));
+    }
+    task::Poll::Ready(Ok(n))
+}
+
+fn write_closed_with_backlog() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "close_notify sent before the buffered writes",
+    )
+}
+
+/// Send what's left of a previously accepted atomic write
+fn poll_write_backlog<IO>(
+    mut inner: Pin<&mut IO>,
+    cx: &mut task::Context<'_>,
+    state: &mut WriteState<'_>,
+) -> task::Poll<io::Result<()>>
+where
+    IO: AsyncWrite,
+{
+    if state.write_backlog.is_some() && state.write_closed.load(Ordering::Relaxed) {
+        // the read side replied to an alert with a close_notify meanwhile,
+        // application data can't follow it
+        *state.write_backlog = None;
+        return Err(write_closed_with_backlog()).into();
+    }
+
+    let max_record_size = state.max_record_size;
+    while let Some((index, backlog)) = state.write_backlog {
+        let mut pending = &backlog[*index..];
+        if let Some(max) = max_record_size {
+            pending = &pending[..std::cmp::min(pending.len(), max)];
+        }
+        let n = futures::ready!(inner.as_mut().poll_write(cx, pending))?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into()).into();
+        }
+        state.stats.record_write(n);
+        *index += n;
+        if *index == backlog.len() {
+            *state.write_backlog = None;
+        }
+    }
+    task::Poll::Ready(Ok(()))
+}
+
+/// Reads through `&KtlsStream`, so an `Arc<KtlsStream>` can be shared by a
+/// reader task and a writer task like a `TcpStream`. Concurrent reads are
+/// serialized.
+impl AsyncRead for &KtlsStream<tokio::net::TcpStream> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        let stream: &KtlsStream<_> = *self;
+        let mut side = lock(&stream.read);
+        let mut state = side.state(&stream.write_closed, &stream.stats);
+        let mut inner = SharedTcpStream::new(&stream.inner);
+        poll_read_with(
+            Pin::new(&mut inner),
+            stream.as_raw_fd(),
+            &mut state,
+            cx,
+            buf,
+        )
+    }
+}
+
+/// Writes through `&KtlsStream`, see the [AsyncRead] implementation.
+/// Concurrent writes are serialized.
+impl AsyncWrite for &KtlsStream<tokio::net::TcpStream> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> task::Poll<io::Result<usize>> {
+        let stream: &KtlsStream<_> = *self;
+        let mut side = lock(&stream.write);
+        let mut state = side.state(&stream.write_closed, &stream.stats);
+        let mut inner = SharedTcpStream::new(&stream.inner);
+        poll_write_with(Pin::new(&mut inner), &mut state, cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> task::Poll<io::Result<usize>> {
+        let stream: &KtlsStream<_> = *self;
+        let mut side = lock(&stream.write);
+        let mut state = side.state(&stream.write_closed, &stream.stats);
+        let mut inner = SharedTcpStream::new(&stream.inner);
+        poll_write_vectored_with(Pin::new(&mut inner), &mut state, cx, bufs)
+    }
 
-        // this ends up closing the inner file descriptor no matter what
-        this.inner.poll_shutdown(cx)
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<io::Result<()>> {
+        let stream: &KtlsStream<_> = *self;
+        let mut side = lock(&stream.write);
+        let mut state = side.state(&stream.write_closed, &stream.stats);
+        let mut inner = SharedTcpStream::new(&stream.inner);
+        poll_flush_with(Pin::new(&mut inner), &mut state, cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        let stream: &KtlsStream<_> = *self;
+        let mut side = lock(&stream.write);
+        let mut state = side.state(&stream.write_closed, &stream.stats);
+        let mut inner = SharedTcpStream::new(&stream.inner);
+        poll_shutdown_with(Pin::new(&mut inner), stream.as_raw_fd(), &mut state, cx)
     }
 }
 
 impl<IO> AsRawFd for KtlsStream<IO>
 where
-    IO: AsRawFd,
+    IO: AsFd,
 {
     fn as_raw_fd(&self) -> std::os::unix::prelude::RawFd {
-        self.inner.as_raw_fd()
+        self.inner.as_fd().as_raw_fd()
+    }
+}
+
+impl<IO> AsFd for KtlsStream<IO>
+where
+    IO: AsFd,
+{
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
     }
 }
 
-#[allow(mutable_transmutes)]
 impl KtlsStream<tokio::net::TcpStream> {
-    pub fn try_io<R>(
-        &self,
-        interest: Interest,
-        f: impl FnOnce() -> std::io::Result<R>,
-    ) -> std::io::Result<R> {
-        self.inner.try_io(interest, f)
+    /// Hand the connection over to blocking code (a C library, a legacy
+    /// thread-per-connection server...). The socket is deregistered from tokio
+    /// and switched back to blocking mode, kTLS stays configured on it.
+    ///
+    /// Also returns the drained plaintext that hasn't been read yet: the new
+    /// owner must consume it before reading from the socket. What atomic or
+    /// coalesced writes buffered is sent first, blocking until the socket
+    /// took it.
+    pub fn into_blocking_fd(self) -> io::Result<(OwnedFd, Option<Vec<u8>>)> {
+        let (drained, inner) = self.into_remaining()?;
+        let inner = inner.into_std()?;
+        inner.set_nonblocking(false)?;
+        Ok((inner.into(), drained))
     }
 
-    pub fn handle_msg(&mut self) {
-        // could be a control message, let's check
-        let this: &mut Self = unsafe { std::mem::transmute(self) };
-        let fd = this.inner.as_raw_fd();
-
-        // XXX: recvmsg wants a `&mut Vec<u8>` so it's able to resize it
-        // I guess? Or so there's a clear separation between uninitialized
-        // and initialized? We could probably get read of that heap alloc, idk.
-
-        // let mut cmsgspace =
-        //     [0u8; unsafe { libc::CMSG_SPACE(std::mem::size_of::<u8>() as _) as _ }];
-        let mut cmsgspace =
-            Vec::with_capacity(unsafe { libc::CMSG_SPACE(std::mem::size_of::<u8>() as _) as _ });
-
-        let mut buf = [0u8; 1024];
-        let mut iov = [IoSliceMut::new(&mut buf[..])];
-        let flags = MsgFlags::empty();
-
-        let r = recvmsg::<SockaddrIn>(fd, &mut iov, Some(&mut cmsgspace), flags);
-        let r = match r {
-            Ok(r) => r,
-            Err(Errno::EAGAIN) => {
-                return;
-            }
-            Err(e) => {
-                // ok I guess it really failed then
-                tracing::trace!(?e, "recvmsg failed");
-                return;
-            }
-        };
-        let cmsg = r
-            .cmsgs()
-            .next()
-            .expect("we should've received exactly one control message");
-
-        let record_type = match cmsg {
-            ControlMessageOwned::TlsGetRecordType(t) => t,
-            _ => panic!("unexpected cmsg type: {cmsg:#?}"),
-        };
+    /// Returns another stream for the same connection, on a `dup(2)` of the
+    /// socket: the kTLS state lives in the kernel socket, so both can read and
+    /// write, and they share the same stats.
+    ///
+    /// Everything else is per-stream: the clone doesn't see the drained
+    /// plaintext nor the data buffered by atomic or coalesced writes, which
+    /// stay with the original, and it only knows about a `close_notify` sent
+    /// or received before it was created. Reading from both at once splits
+    /// the incoming data between them in no particular order, so the
+    /// intended use is one reader plus a writer (e.g. a heartbeat task).
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let fd = self.inner.as_fd().try_clone_to_owned()?;
+        let inner = tokio::net::TcpStream::from_std(std::net::TcpStream::from(fd))?;
 
-        match TlsRecordType::from_primitive(record_type) {
-            TlsRecordType::ChangeCipherSpec => {
-                panic!("change_cipher_spec isn't supported by the ktls crate")
+        let read = lock(&self.read);
+        let write = lock(&self.write);
+        Ok(Self {
+            inner,
+            write_closed: AtomicBool::new(self.write_closed.load(Ordering::Relaxed)),
+            read: Mutex::new(ReadSide {
+                read_closed: read.read_closed,
+                read_failure: read.read_failure,
+                record_reads: read.record_reads,
+                control_record_handler: read.control_record_handler.clone(),
+                ..ReadSide::new(None)
+            }),
+            write: Mutex::new(WriteSide {
+                atomic_writes: write.atomic_writes,
+                coalesce_writes: write.coalesce_writes,
+                max_record_size: write.max_record_size,
+                shutdown_mode: write.shutdown_mode,
+                ..WriteSide::new()
+            }),
+            stats: self.stats.clone(),
+            info: self.info.clone(),
+            extensions: Default::default(),
+            keying_material: self.keying_material.clone(),
+        })
+    }
+
+    /// Like [tokio::net::TcpStream::into_std]: deregisters the socket from
+    /// tokio and returns it as a [std::net::TcpStream], still in non-blocking
+    /// mode (see [KtlsStream::into_blocking_fd] otherwise). kTLS stays
+    /// configured on it.
+    ///
+    /// Also returns the drained plaintext that hasn't been read yet: the new
+    /// owner must consume it before reading from the socket. What atomic or
+    /// coalesced writes buffered is sent first, blocking until the socket
+    /// took it.
+    pub fn into_std(self) -> io::Result<(std::net::TcpStream, Option<Vec<u8>>)> {
+        let (drained, inner) = self.into_remaining()?;
+        Ok((inner.into_std()?, drained))
+    }
+
+    /// Send `count` bytes of `file`, starting at `offset`, with sendfile(2):
+    /// the kernel encrypts straight from the page cache. `progress` is called
+    /// after each chunk with the total number of bytes sent so far. Returns
+    /// the number of bytes sent, which is less than `count` if the end of the
+    /// file was reached.
+    ///
+    /// Dropping the future stops the transfer at a chunk boundary. Every chunk
+    /// handed to the kernel is a regular write on the TLS stream, so nothing
+    /// is left dangling and the stream can keep being used afterwards.
+    pub async fn sendfile(
+        &mut self,
+        file: &impl AsFd,
+        offset: u64,
+        count: u64,
+        mut progress: impl FnMut(u64),
+    ) -> io::Result<u64> {
+        const CHUNK_SIZE: u64 = 1024 * 1024;
+
+        // what earlier writes left buffered goes out before the file
+        futures::future::poll_fn(|cx| {
+            let (inner, _, mut write_state) = self.split_states();
+            poll_write_backlog(Pin::new(inner), cx, &mut write_state)
+        })
+        .await?;
+
+        let fd = self.inner.as_fd().as_raw_fd();
+        let file_fd = file.as_fd().as_raw_fd();
+        let mut sent = 0;
+
+        while sent < count {
+            if self.write_closed.load(Ordering::Relaxed) {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "write closed"));
             }
-            TlsRecordType::Alert => {
-                // the alert level and description are in iovs
-                let iov = r.iovs().next().expect("expected data in iovs");
-
-                let (level, description) = match iov {
-                    [] => {
-                        // we have an early return case for that
-                        unreachable!();
-                    }
-                    &[level] => {
-                        // https://github.com/facebookincubator/fizz/blob/fff6d9d49d3c554ab66b58822d1e1fe93e8d80f2/fizz/experimental/ktls/AsyncKTLSSocket.cpp#L144
-                        //
-                        // Since all alerts (even warning-level alerts)
-                        // signal the abort of a TLS session, we do not
-                        // need to worry about additional application
-                        // data.
-                        //
-                        // If we only have half the alert (because the
-                        // user passed a buffer of size 1), just assume
-                        // it's a close_notify
-                        (
-                            TlsAlertLevel::from_primitive(level),
-                            TlsAlertDescription::CloseNotify,
-                        )
-                    }
-                    &[level, description] => (
-                        TlsAlertLevel::from_primitive(level),
-                        TlsAlertDescription::from_primitive(description),
-                    ),
-                    _ => {
-                        unreachable!("TLS alerts are exactly 2 bytes, your kTLS is misbehaving");
-                    }
-                };
-
-                match (level, description) {
-                    // https://datatracker.ietf.org/doc/html/rfc5246#section-7.2
-                    // alerts we should handle are ones with fatal level or a
-                    // close_notify
-                    (_, TlsAlertDescription::CloseNotify) | (TlsAlertLevel::Fatal, _) => {
-                        tracing::trace!(?level, ?description, "got TLS alert");
-                        this.read_closed = true;
-                        this.write_closed = true;
-                        if let Err(_e) = crate::ffi::send_close_notify(this.inner.as_raw_fd()) {}
-                        // the file descriptor will be closed when the stream is dropped,
-                        // we already protect against writes-after-close_notify through
-                        // the write_closed flag
-                    }
-                    _ => {
-                        // we got something we probably can't handle
-                    }
+
+            let chunk = std::cmp::min(count - sent, CHUNK_SIZE) as usize;
+            let n = loop {
+                self.inner.writable().await?;
+                match self.inner.try_io(Interest::WRITABLE, || {
+                    crate::ffi::sendfile(fd, file_fd, offset + sent, chunk)
+                }) {
+                    Ok(n) => break n,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
                 }
+            };
+            if n == 0 {
+                tracing::trace!(%sent, "sendfile reached end of file");
+                break;
             }
-            TlsRecordType::Handshake => {
-                // TODO: this is where we receive TLS 1.3 resumption tickets,
-                // should those be stored anywhere? I'm not even sure what
-                // format they have at this point
-                tracing::trace!("ignoring handshake message (probably a resumption ticket)");
+
+            self.stats.record_write(n);
+            sent += n as u64;
+            progress(sent);
+        }
+
+        Ok(sent)
+    }
+
+    /// Reads into several buffers at once with a single `readv`, starting with
+    /// the plaintext drained from rustls. tokio's `AsyncRead` has no vectored
+    /// reads, hence the inherent method. Goes through the same checks as
+    /// `poll_read`.
+    pub fn poll_read_vectored(
+        &mut self,
+        cx: &mut task::Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> task::Poll<io::Result<usize>> {
+        let fd = self.inner.as_fd().as_raw_fd();
+        let mut state = get_mut(&mut self.read).state(&self.write_closed, &self.stats);
+
+        if let Some(e) = state.pending_read_error.take() {
+            return task::Poll::Ready(Err(e));
+        }
+        futures::ready!(poll_budget(cx));
+
+        if let Some((drain_index, drained)) = state.drained.as_mut() {
+            let mut read = 0;
+            for buf in bufs.iter_mut() {
+                let remaining = &drained[*drain_index..];
+                let len = std::cmp::min(buf.len(), remaining.len());
+                buf[..len].copy_from_slice(&remaining[..len]);
+                *drain_index += len;
+                read += len;
             }
-            TlsRecordType::ApplicationData => {
-                unreachable!("received TLS application in recvmsg, this is supposed to happen in the poll_read codepath")
+            if *drain_index >= drained.len() {
+                *state.drained = None;
             }
-            TlsRecordType::Other(t) => {
-                // just ignore the record?
-                tracing::trace!("received record_type {t:#?}");
+            *state.at_record_boundary = false;
+            state.stats.record_read(read);
+            return task::Poll::Ready(Ok(read));
+        }
+        if let Some(res) = read_side_done(&state) {
+            return task::Poll::Ready(res.map(|()| 0));
+        }
+
+        loop {
+            futures::ready!(self.inner.poll_read_ready(cx))?;
+            match self.inner.try_read_vectored(bufs) {
+                Ok(n) => {
+                    state.stats.record_read(n);
+                    return task::Poll::Ready(Ok(n));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                // see the control message handling in `poll_read`
+                Err(e) if e.raw_os_error() == Some(5) => {
+                    return poll_control_record(fd, &mut state, cx).map_ok(|()| 0);
+                }
+                Err(e) => return task::Poll::Ready(Err(e)),
             }
         }
     }
 
+    /// See [KtlsStream::poll_read_vectored]
+    pub async fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        futures::future::poll_fn(|cx| self.poll_read_vectored(cx, bufs)).await
+    }
+
+    /// Like [tokio::net::TcpStream::poll_peek]: reads data without removing it
+    /// from the stream, starting with the plaintext drained from rustls, so
+    /// protocol sniffing can be done after the handshake. Goes through the
+    /// same checks as `poll_read`: control messages met on the way are
+    /// handled (and consumed), returns 0 once the peer closed the TLS
+    /// session, and the error the next read will return.
+    pub fn poll_peek(
+        &mut self,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> task::Poll<io::Result<usize>> {
+        let fd = self.inner.as_fd().as_raw_fd();
+        let mut state = get_mut(&mut self.read).state(&self.write_closed, &self.stats);
+
+        if let Some(e) = state.pending_read_error.as_ref() {
+            return task::Poll::Ready(Err(peek_error(e)));
+        }
+        futures::ready!(poll_budget(cx));
+
+        if let Some((drain_index, drained)) = state.drained.as_ref() {
+            let drained = &drained[*drain_index..];
+            let len = std::cmp::min(buf.remaining(), drained.len());
+            buf.put_slice(&drained[..len]);
+            return task::Poll::Ready(Ok(len));
+        }
+        if let Some(res) = read_side_done(&state) {
+            return task::Poll::Ready(res.map(|()| 0));
+        }
+
+        match futures::ready!(self.inner.poll_peek(cx, buf)) {
+            // see the control message handling in `poll_read`
+            Err(e) if e.raw_os_error() == Some(5) => {
+                poll_control_record(fd, &mut state, cx).map_ok(|()| 0)
+            }
+            res => task::Poll::Ready(res),
+        }
+    }
+
+    /// Like [tokio::net::TcpStream::peek], see [KtlsStream::poll_peek]
+    pub async fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        futures::future::poll_fn(|cx| self.poll_peek(cx, &mut buf)).await
+    }
+
+    /// Like [tokio::net::TcpStream::ready]. Reports the stream as readable
+    /// while there's drained plaintext left to read.
+    pub async fn ready(&self, interest: Interest) -> io::Result<Ready> {
+        if interest.is_readable() && lock(&self.read).drained.is_some() {
+            return Ok(Ready::READABLE);
+        }
+        self.inner.ready(interest).await
+    }
+
+    /// Like [tokio::net::TcpStream::readable], see [KtlsStream::ready]
+    pub async fn readable(&self) -> io::Result<()> {
+        self.ready(Interest::READABLE).await.map(|_| ())
+    }
+
+    /// Like [tokio::net::TcpStream::writable]
+    pub async fn writable(&self) -> io::Result<()> {
+        self.inner.writable().await
+    }
+
+    /// Like [tokio::net::TcpStream::try_read]: reads without waiting, fails
+    /// with [io::ErrorKind::WouldBlock] if nothing is available. Goes through
+    /// the same path as `poll_read`, so drained data and control messages are
+    /// handled as usual, the latter also resulting in `WouldBlock`.
+    pub fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(self).poll_read(&mut noop_context(), &mut buf) {
+            task::Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+            task::Poll::Ready(Err(e)) => Err(e),
+            task::Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    /// Like [tokio::net::TcpStream::try_write]: writes without waiting, fails
+    /// with [io::ErrorKind::WouldBlock] if the socket isn't ready. Goes through
+    /// the same path as `poll_write`, so atomic writes, coalescing and record
+    /// size caps apply.
+    pub fn try_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(self).poll_write(&mut noop_context(), buf) {
+            task::Poll::Ready(res) => res,
+            task::Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    pub fn try_io<R>(
+        &self,
+        interest: Interest,
+        f: impl FnOnce() -> std::io::Result<R>,
+    ) -> std::io::Result<R> {
+        self.inner.try_io(interest, f)
+    }
+
+    /// Receives and acts on the control record a read ran into, see
+    /// [KtlsStream::with_control_record_handler]
+    pub fn handle_msg(&mut self) {
+        let fd = self.inner.as_fd().as_raw_fd();
+        let (_, mut state, _) = self.split_states();
+        if let Err(e) = recv_control_record(fd, &mut state) {
+            tracing::debug!(%e, "control record");
+        }
+    }
+
     pub fn poll_read_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         self.inner.poll_read_ready(cx)
     }
 
     pub fn poll_write_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        if self.write_closed {
+        if self.write_closed.load(Ordering::Relaxed) {
             return task::Poll::Ready(Err(io::Error::new(
                 io::ErrorKind::BrokenPipe,
                 "write closed",
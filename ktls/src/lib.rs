@@ -1,13 +1,21 @@
+pub use ffi::Direction;
 use ffi::{setup_tls_info, setup_ulp, KtlsCompatibilityError};
 use futures::future::try_join_all;
-use rustls::{Connection, ConnectionTrafficSecrets, SupportedCipherSuite};
+use rustls::{Connection, ConnectionTrafficSecrets, ExtractedSecrets, SupportedCipherSuite};
 use smallvec::SmallVec;
 use std::{
-    io,
-    os::unix::prelude::{AsRawFd, RawFd},
+    io::{self, Read},
+    os::fd::{AsFd, AsRawFd, RawFd},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    task::Poll,
+    time::Duration,
 };
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     net::{TcpListener, TcpStream},
 };
 
@@ -18,26 +26,123 @@ mod async_read_ready;
 pub use async_read_ready::AsyncReadReady;
 
 mod ktls_stream;
-pub use ktls_stream::KtlsStream;
+pub use ktls_stream::{AlertLevel, KtlsStream, ShutdownMode};
 
 mod cork_stream;
 pub use cork_stream::CorkStream;
+use cork_stream::Hellos;
+
+mod close_on_drop;
+pub use close_on_drop::CloseNotifyOnDrop;
+
+mod split;
+pub use split::{KtlsReadHalf, KtlsReadHalfRef, KtlsWriteHalf, KtlsWriteHalfRef};
+
+mod fd_stream;
+pub use fd_stream::FdStream;
+
+mod connection_info;
+pub use connection_info::{ConnectionInfo, HandshakeKind};
+
+mod acceptor;
+pub use acceptor::{AcceptedStream, KtlsAcceptor};
+
+mod connector;
+pub use connector::{connect, connect_via_socks5, KtlsConnector};
+
+mod config;
+use config::SessionTickets;
+pub use config::{KtlsConfig, RekeyPolicy};
+
+mod config_ext;
+pub use config_ext::{KtlsClientConfigExt, KtlsServerConfigExt};
+
+mod maybe_ktls_stream;
+pub use maybe_ktls_stream::MaybeKtlsStream;
+
+mod serve;
+pub use serve::{serve, serve_with};
+
+pub mod bridge;
+pub mod raw;
+pub mod socks5;
+pub mod stats;
+
+mod tcp_info;
+pub use tcp_info::TcpInfo;
+
+mod probe;
+pub use probe::ProbeStrategy;
+
+mod kernel_support;
+pub use kernel_support::KernelSupport;
+
+mod hw_offload;
+pub use hw_offload::HwOffload;
+
+mod ktls_info;
+pub use ktls_info::{KtlsCipher, KtlsDirectionInfo, KtlsInfo};
+
+mod control_record;
+pub use control_record::{ControlRecord, ControlRecordHandler};
+
+mod extensions;
+pub use extensions::Extensions;
+
+mod ticket_counter;
+pub use ticket_counter::TicketCounter;
+
+mod keying_material;
+use keying_material::ExportedKeyingMaterial;
+pub use keying_material::KeyingMaterialExport;
+
+#[cfg(feature = "cdylib")]
+pub mod capi;
+
+#[cfg(feature = "python")]
+mod python;
 
 #[derive(Debug, Default)]
 pub struct CompatibleCiphers {
     pub tls12: CompatibleCiphersForVersion,
     pub tls13: CompatibleCiphersForVersion,
+    pub versions: CompatibleVersions,
 }
 
+/// Per direction, since kernels got RX support for each cipher later than TX
 #[derive(Debug, Default)]
 pub struct CompatibleCiphersForVersion {
-    pub aes_gcm_128: bool,
-    pub aes_gcm_256: bool,
-    pub chacha20_poly1305: bool,
+    pub aes_gcm_128: CompatibleDirections,
+    pub aes_gcm_256: CompatibleDirections,
+    pub chacha20_poly1305: CompatibleDirections,
+}
+
+/// Whether a TLS protocol version can be offloaded at all, per direction.
+/// Support for TLS 1.3 RX landed in the kernel (5.2) much later than TLS 1.2
+/// TX (4.13), so this is probed independently of the individual ciphers.
+#[derive(Debug, Default)]
+pub struct CompatibleVersions {
+    pub tls12: CompatibleDirections,
+    pub tls13: CompatibleDirections,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CompatibleDirections {
+    pub tx: bool,
+    pub rx: bool,
+}
+
+impl CompatibleDirections {
+    /// Both directions can be offloaded, which `config_ktls_*` need
+    pub fn both(self) -> bool {
+        self.tx && self.rx
+    }
 }
 
 impl CompatibleCiphers {
     const CIPHERS_COUNT: usize = 6;
+    const VERSIONS_COUNT: usize = 2;
+    const PROBES_COUNT: usize = Self::CIPHERS_COUNT + Self::VERSIONS_COUNT;
 
     /// List compatible ciphers. This listens on a TCP socket and blocks for a
     /// little while. Do once at the very start of a program. Should probably be
@@ -49,7 +154,7 @@ impl CompatibleCiphers {
         let local_addr = ln.local_addr()?;
 
         // Accepted conns of ln
-        let mut accepted_conns: SmallVec<[TcpStream; Self::CIPHERS_COUNT]> = SmallVec::new();
+        let mut accepted_conns: SmallVec<[TcpStream; Self::PROBES_COUNT]> = SmallVec::new();
 
         let accept_conns_fut = async {
             loop {
@@ -60,7 +165,7 @@ impl CompatibleCiphers {
         };
 
         let create_connections_fut =
-            try_join_all((0..Self::CIPHERS_COUNT).map(|_| TcpStream::connect(local_addr)));
+            try_join_all((0..Self::PROBES_COUNT).map(|_| TcpStream::connect(local_addr)));
 
         let socks = tokio::select! {
             // Use biased here to optimize performance.
@@ -82,12 +187,58 @@ impl CompatibleCiphers {
             _ = accept_conns_fut => unreachable!(),
         };
 
-        ciphers.test_ciphers((&*socks).try_into().unwrap());
+        ciphers.probe(&socks);
+        Ok(ciphers)
+    }
+
+    /// Like [CompatibleCiphers::new], with blocking sockets: for `main()`
+    /// before the runtime is started, or for CLI tools without one. See
+    /// [CompatibleCiphers::new_with] for ways to probe without a listener.
+    pub fn new_sync() -> io::Result<Self> {
+        let mut ciphers = CompatibleCiphers::default();
+
+        let ln = std::net::TcpListener::bind("0.0.0.0:0")?;
+        let local_addr = ln.local_addr()?;
+
+        // the connections are established as soon as they're in the
+        // listener's backlog, no need to accept them
+        let socks = (0..Self::PROBES_COUNT)
+            .map(|_| std::net::TcpStream::connect(local_addr))
+            .collect::<io::Result<Vec<_>>>()?;
 
+        ciphers.probe(&socks);
         Ok(ciphers)
     }
 
-    fn test_ciphers(&mut self, socks: &[TcpStream; Self::CIPHERS_COUNT]) {
+    fn probe(&mut self, socks: &[impl AsFd]) {
+        let (cipher_socks, version_socks) = socks.split_at(Self::CIPHERS_COUNT);
+        self.test_ciphers(cipher_socks.try_into().unwrap());
+        self.test_versions(version_socks.try_into().unwrap());
+    }
+
+    fn test_versions(&mut self, socks: &[impl AsFd; Self::VERSIONS_COUNT]) {
+        // AES-128-GCM is the one cipher every kTLS-capable kernel supports, so
+        // it's used as a stand-in to probe the protocol version itself.
+        let versions = [
+            (
+                rustls::cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+                &mut self.versions.tls12,
+            ),
+            (
+                rustls::cipher_suite::TLS13_AES_128_GCM_SHA256,
+                &mut self.versions.tls13,
+            ),
+        ];
+
+        versions
+            .into_iter()
+            .zip(socks)
+            .for_each(|((cipher_suite, field), sock)| {
+                *field = sample_setup(sock, cipher_suite);
+            });
+    }
+
+    fn test_ciphers(&mut self, socks: &[impl AsFd; Self::CIPHERS_COUNT]) {
         let ciphers = [
             (
                 rustls::cipher_suite::TLS13_AES_128_GCM_SHA256,
@@ -121,13 +272,19 @@ impl CompatibleCiphers {
             .into_iter()
             .zip(socks)
             .for_each(|((cipher_suite, field), sock)| {
-                *field = sample_cipher_setup(sock, cipher_suite).is_ok();
+                *field = sample_setup(sock, cipher_suite);
             });
     }
 
     /// Returns true if we're reasonably confident that functions like
     /// [config_ktls_client] and [config_ktls_server] will succeed.
     pub fn is_compatible(&self, suite: &SupportedCipherSuite) -> bool {
+        self.directions(suite).both()
+    }
+
+    /// Which directions of a connection that negotiated `suite` the kernel
+    /// can offload
+    pub fn directions(&self, suite: &SupportedCipherSuite) -> CompatibleDirections {
         let (fields, bulk) = match suite {
             SupportedCipherSuite::Tls12(suite) => (&self.tls12, &suite.common.bulk),
             SupportedCipherSuite::Tls13(suite) => (&self.tls13, &suite.common.bulk),
@@ -138,14 +295,76 @@ impl CompatibleCiphers {
             rustls::BulkAlgorithm::Chacha20Poly1305 => fields.chacha20_poly1305,
         }
     }
+
+    /// The suites out of `suites` that [CompatibleCiphers::is_compatible]
+    /// accepts, in the same order, e.g. to build a rustls config with
+    /// `with_cipher_suites(&ciphers.filter_suites(rustls::ALL_CIPHER_SUITES))`,
+    /// or see [CompatibleCiphers::server_config_builder]
+    pub fn filter_suites(&self, suites: &[SupportedCipherSuite]) -> Vec<SupportedCipherSuite> {
+        suites
+            .iter()
+            .copied()
+            .filter(|suite| self.is_compatible(suite))
+            .collect()
+    }
+
+    /// Returns true if the kernel can offload both directions of the given
+    /// protocol version, regardless of which cipher ends up being negotiated.
+    pub fn is_version_compatible(&self, version: rustls::ProtocolVersion) -> bool {
+        let directions = match version {
+            rustls::ProtocolVersion::TLSv1_2 => self.versions.tls12,
+            rustls::ProtocolVersion::TLSv1_3 => self.versions.tls13,
+            _ => return false,
+        };
+        directions.tx && directions.rx
+    }
+}
+
+/// What the kernel supports, probed with [ProbeStrategy::SelfConnect] the
+/// first time it's needed and cached for the whole process, `None` if probing
+/// failed. The probe takes a few syscalls and blocks: call this once at
+/// startup to keep it off connection paths.
+pub fn compatible_ciphers() -> Option<&'static CompatibleCiphers> {
+    static CIPHERS: OnceLock<Option<CompatibleCiphers>> = OnceLock::new();
+    CIPHERS
+        .get_or_init(|| {
+            CompatibleCiphers::new_with(ProbeStrategy::SelfConnect)
+                .map_err(|e| tracing::warn!(%e, "failed to probe kTLS support"))
+                .ok()
+        })
+        .as_ref()
+}
+
+/// Whether the kernel can offload connections that negotiated `suite`, see
+/// [compatible_ciphers]. False if probing failed.
+pub fn kernel_supports(suite: &SupportedCipherSuite) -> bool {
+    compatible_ciphers().is_some_and(|ciphers| ciphers.is_compatible(suite))
+}
+
+fn sample_setup(sock: &impl AsFd, cipher_suite: SupportedCipherSuite) -> CompatibleDirections {
+    let fd = sock.as_fd().as_raw_fd();
+
+    if setup_ulp(fd).is_err() {
+        return CompatibleDirections::default();
+    }
+
+    let probe = |dir| {
+        let info = CryptoInfo::from_rustls(cipher_suite, (0, zero_secrets(cipher_suite))).unwrap();
+        setup_tls_info(fd, dir, info).is_ok()
+    };
+
+    CompatibleDirections {
+        tx: probe(ffi::Direction::Tx),
+        rx: probe(ffi::Direction::Rx),
+    }
 }
 
-fn sample_cipher_setup(sock: &TcpStream, cipher_suite: SupportedCipherSuite) -> Result<(), Error> {
+fn zero_secrets(cipher_suite: SupportedCipherSuite) -> ConnectionTrafficSecrets {
     let bulk_algo = match cipher_suite {
         SupportedCipherSuite::Tls12(suite) => &suite.common.bulk,
         SupportedCipherSuite::Tls13(suite) => &suite.common.bulk,
     };
-    let zero_secrets = match bulk_algo {
+    match bulk_algo {
         rustls::BulkAlgorithm::Aes128Gcm => ConnectionTrafficSecrets::Aes128Gcm {
             key: Default::default(),
             salt: Default::default(),
@@ -160,39 +379,230 @@ fn sample_cipher_setup(sock: &TcpStream, cipher_suite: SupportedCipherSuite) ->
             key: Default::default(),
             iv: Default::default(),
         },
-    };
-
-    let seq_secrets = (0, zero_secrets);
-    let info = CryptoInfo::from_rustls(cipher_suite, seq_secrets).unwrap();
-
-    let fd = sock.as_raw_fd();
-
-    setup_ulp(fd).map_err(Error::UlpError)?;
-
-    setup_tls_info(fd, ffi::Direction::Tx, info)?;
-
-    Ok(())
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    #[error("failed to connect: {0}")]
+    ConnectError(#[source] std::io::Error),
+
+    #[error("TLS handshake failed: {0}")]
+    HandshakeError(#[source] std::io::Error),
+
+    #[error("no server config was selected for the ClientHello")]
+    ClientHelloRejected,
+
     #[error("failed to enable TLS ULP (upper level protocol): {0}")]
     UlpError(#[source] std::io::Error),
 
+    #[error(
+        "the `tls` ULP (upper level protocol) isn't available: is the `tls` kernel module loaded?"
+    )]
+    UlpNotAvailable,
+
+    #[error("another ULP (upper level protocol) than `tls` is attached to the socket")]
+    OtherUlpAttached,
+
+    #[error("kTLS is already set up on this socket")]
+    AlreadyOffloaded,
+
     #[error("kTLS compatibility error: {0}")]
     KtlsCompatibility(#[from] KtlsCompatibilityError),
 
     #[error("failed to export secrets")]
     ExportSecrets(#[source] rustls::Error),
 
-    #[error("failed to configure tx/rx (unsupported cipher?): {0}")]
-    TlsCryptoInfoError(#[source] std::io::Error),
+    #[error("secret extraction is disabled: set `enable_secret_extraction = true` on the rustls ClientConfig/ServerConfig")]
+    SecretExtractionDisabled,
+
+    #[error("extracted {direction:?} secrets for {cipher_suite:?} can't be offloaded to kTLS")]
+    UnsupportedSecrets {
+        cipher_suite: SupportedCipherSuite,
+        direction: Direction,
+    },
+
+    #[error("setsockopt(TLS_{direction:?}) failed (unsupported cipher?): {source}")]
+    Setsockopt {
+        direction: Direction,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("failed to query the kTLS state of the socket: {0}")]
+    CryptoInfoQuery(#[source] std::io::Error),
 
     #[error("an I/O occured while draining the rustls stream: {0}")]
     DrainError(#[source] std::io::Error),
 
+    #[error("timed out after {0:?} while draining the rustls stream")]
+    DrainTimedOut(Duration),
+
+    #[error("the peer sent more than {0} bytes before the offload")]
+    DrainLimitExceeded(usize),
+
+    #[error("timed out after {0:?} while accepting and offloading a connection")]
+    AcceptTimedOut(Duration),
+
+    #[error("too many handshakes in progress and waiting (see `KtlsAcceptor::with_max_queued_handshakes`)")]
+    TooManyHandshakes,
+
+    #[error("timed out after {0:?} while connecting and offloading a connection")]
+    ConnectTimedOut(Duration),
+
+    #[error("{cipher_suite:?} isn't a {version:?} cipher suite")]
+    VersionMismatch {
+        version: rustls::ProtocolVersion,
+        cipher_suite: SupportedCipherSuite,
+    },
+
+    #[error("rustls has data left to write: flush it with `write_tls` before offloading")]
+    PendingWrites,
+
     #[error("no negotiated cipher suite: call config_ktls_* only /after/ the handshake")]
     NoNegotiatedCipherSuite,
+
+    #[error("kTLS offload is disabled (see {DISABLE_ENV_VAR} and `KtlsConfig::with_offload`)")]
+    OffloadDisabled,
+
+    #[error("TLS 1.3 connections stay in userspace (see `KtlsConfig::with_rekey_policy`)")]
+    RekeyUnsupported,
+
+    #[error("failed to export keying material: {0}")]
+    ExportKeyingMaterial(#[source] rustls::Error),
+
+    #[error("this keying material wasn't requested before offloading")]
+    KeyingMaterialNotExported,
+
+    /// A read fails with this (wrapped in an [io::Error]) when a TLS 1.3
+    /// server asks an offloaded client for a certificate after the handshake:
+    /// answering takes the handshake state rustls had, which is gone by then.
+    /// The server decides whether the connection goes on without it.
+    #[error("the server requested post-handshake client authentication, which offloaded connections can't do")]
+    PostHandshakeAuthRequested,
+
+    /// Reads fail with this (wrapped in an [io::Error]) once the peer sent a
+    /// fatal alert, all of them. A close_notify is a clean EOF instead.
+    #[error("received a {level:?} {description:?} alert")]
+    Alert {
+        level: AlertLevel,
+        description: rustls::AlertDescription,
+    },
+}
+
+impl Error {
+    /// The errno of the failed syscall, if this error comes from one
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Error::ConnectError(e)
+            | Error::HandshakeError(e)
+            | Error::UlpError(e)
+            | Error::Setsockopt { source: e, .. }
+            | Error::CryptoInfoQuery(e)
+            | Error::DrainError(e) => e.raw_os_error(),
+            Error::UlpNotAvailable => Some(libc::ENOENT),
+            Error::AlreadyOffloaded => Some(libc::EEXIST),
+            _ => None,
+        }
+    }
+
+    fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            Error::ConnectError(e)
+            | Error::HandshakeError(e)
+            | Error::UlpError(e)
+            | Error::Setsockopt { source: e, .. }
+            | Error::CryptoInfoQuery(e)
+            | Error::DrainError(e) => e.kind(),
+            Error::DrainTimedOut(_) | Error::AcceptTimedOut(_) | Error::ConnectTimedOut(_) => {
+                io::ErrorKind::TimedOut
+            }
+            Error::UlpNotAvailable
+            | Error::KtlsCompatibility(_)
+            | Error::UnsupportedSecrets { .. }
+            | Error::OffloadDisabled
+            | Error::RekeyUnsupported
+            | Error::PostHandshakeAuthRequested => io::ErrorKind::Unsupported,
+            Error::Alert { .. } => io::ErrorKind::ConnectionAborted,
+            Error::VersionMismatch { .. } => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+/// The [Error] stays available through [io::Error::get_ref] and
+/// [io::Error::into_inner], to be downcast.
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        io::Error::new(err.io_kind(), err)
+    }
+}
+
+/// What `config_ktls_*_or_return` fail with: `stream` is the untouched rustls
+/// stream if the offload failed before anything was taken out of it, so the
+/// connection can go on in userspace. Past that point (draining, extracting
+/// the secrets, installing them), the session is lost with the error.
+pub struct OffloadError<S> {
+    pub error: Error,
+    pub stream: Option<S>,
+}
+
+impl<S> std::fmt::Debug for OffloadError<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OffloadError")
+            .field("error", &self.error)
+            .field("stream", &self.stream.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+impl<S> std::fmt::Display for OffloadError<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl<S> std::error::Error for OffloadError<S> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        std::error::Error::source(&self.error)
+    }
+}
+
+/// Setting this environment variable to `1` turns kTLS offload off for the
+/// whole process: [KtlsAcceptor], [KtlsConnector], [connect] and
+/// `config_ktls_*_or_fallback` keep connections on rustls, and
+/// `config_ktls_*_or_return` give the stream back. [config_ktls_server],
+/// [config_ktls_client] and their `_with_config` variants ignore it, calling
+/// them is asking for the offload. It is read once, the first time it's
+/// needed, and [set_enabled] overrides it.
+/// [KtlsConfig::with_offload] does the same for a single config.
+pub const DISABLE_ENV_VAR: &str = "KTLS_DISABLE";
+
+fn enabled_flag() -> &'static AtomicBool {
+    static ENABLED: OnceLock<AtomicBool> = OnceLock::new();
+    ENABLED.get_or_init(|| {
+        let disabled = std::env::var(DISABLE_ENV_VAR).is_ok_and(|v| v == "1");
+        if disabled {
+            tracing::warn!("kTLS offload disabled through {DISABLE_ENV_VAR}");
+        }
+        AtomicBool::new(!disabled)
+    })
+}
+
+/// Returns false if offload was turned off through [DISABLE_ENV_VAR] or
+/// [set_enabled]. `config_ktls_*_or_return` and `config_ktls_*_or_fallback`
+/// check it (and [KtlsConfig::with_offload]) themselves.
+pub fn offload_enabled() -> bool {
+    enabled_flag().load(Ordering::Relaxed)
+}
+
+/// Turns kTLS offload off (or back on) for the whole process at runtime, like
+/// [DISABLE_ENV_VAR] does at startup. Only connections set up afterwards are
+/// affected: those already offloaded stay offloaded.
+pub fn set_enabled(enabled: bool) {
+    if enabled_flag().swap(enabled, Ordering::Relaxed) != enabled {
+        tracing::warn!(%enabled, "kTLS offload toggled");
+    }
 }
 
 /// Configure kTLS for this socket. If this call succeeds, data can be written
@@ -203,18 +613,119 @@ pub enum Error {
 /// The inner IO type must be wrapped in [CorkStream] since it's the only way
 /// to drain a rustls stream cleanly. See its documentation for details.
 pub async fn config_ktls_server<IO>(
+    stream: tokio_rustls::server::TlsStream<CorkStream<IO>>,
+) -> Result<KtlsStream<IO>, Error>
+where
+    IO: AsFd + AsyncRead + AsyncReadReady + AsyncWrite + Unpin,
+{
+    config_ktls_server_inner(stream, DrainOptions::default(), &[]).await
+}
+
+/// Like [config_ktls_server], with the options in `config`
+pub async fn config_ktls_server_with_config<IO>(
+    stream: tokio_rustls::server::TlsStream<CorkStream<IO>>,
+    config: &KtlsConfig,
+) -> Result<KtlsStream<IO>, Error>
+where
+    IO: AsFd + AsyncRead + AsyncReadReady + AsyncWrite + Unpin,
+{
+    check_config(stream.get_ref().1, config)?;
+    let stream = config_ktls_server_inner(stream, config.drain, &config.exports).await?;
+    Ok(stream.with_shutdown_mode(config.shutdown_mode))
+}
+
+/// Like [config_ktls_server_with_config], but gives the rustls stream back in
+/// [OffloadError] when the offload can't even start: offload disabled or
+/// ruled out by `config`, negotiated cipher not supported by the kernel, ULP
+/// unavailable or taken by another protocol, kTLS already set up. Secret
+/// extraction being disabled is only found out once the stream is consumed:
+/// [KtlsAcceptor] and [KtlsConnector] check their rustls config for it
+/// beforehand.
+pub async fn config_ktls_server_or_return<IO>(
+    stream: tokio_rustls::server::TlsStream<CorkStream<IO>>,
+    config: &KtlsConfig,
+) -> Result<KtlsStream<IO>, OffloadError<tokio_rustls::server::TlsStream<CorkStream<IO>>>>
+where
+    IO: AsFd + AsyncRead + AsyncReadReady + AsyncWrite + Unpin,
+{
+    let (io, conn) = stream.get_ref();
+    if let Err(error) = check_offloadable(io.as_fd().as_raw_fd(), conn, config) {
+        return Err(OffloadError {
+            error,
+            stream: Some(stream),
+        });
+    }
+
+    config_ktls_server_with_config(stream, config)
+        .await
+        .map_err(|error| OffloadError {
+            error,
+            stream: None,
+        })
+}
+
+/// Like [config_ktls_server_or_return], but keeps the connection on rustls
+/// when the offload can't start (e.g. the kernel has no kTLS, or doesn't
+/// support the negotiated cipher, see [KtlsConfig::with_compatible_ciphers]).
+/// Only errors past that point are returned.
+pub async fn config_ktls_server_or_fallback<IO>(
+    stream: tokio_rustls::server::TlsStream<CorkStream<IO>>,
+    config: &KtlsConfig,
+) -> Result<MaybeKtlsStream<IO>, Error>
+where
+    IO: AsFd + AsyncRead + AsyncReadReady + AsyncWrite + Unpin,
+{
+    match config_ktls_server_or_return(stream, config).await {
+        Ok(stream) => Ok(MaybeKtlsStream::Ktls(stream)),
+        Err(OffloadError {
+            error,
+            stream: Some(stream),
+        }) => {
+            tracing::debug!(%error, "can't offload, staying in userspace");
+            Ok(MaybeKtlsStream::Rustls(Box::new(stream.into())))
+        }
+        Err(OffloadError { error, .. }) => Err(error),
+    }
+}
+
+async fn config_ktls_server_inner<IO>(
     mut stream: tokio_rustls::server::TlsStream<CorkStream<IO>>,
+    drain: DrainOptions,
+    exports: &[KeyingMaterialExport],
 ) -> Result<KtlsStream<IO>, Error>
 where
-    IO: AsRawFd + AsyncRead + AsyncReadReady + AsyncWrite + Unpin,
+    IO: AsFd + AsyncRead + AsyncReadReady + AsyncWrite + Unpin,
 {
     stream.get_mut().0.corked = true;
-    let drained = drain(&mut stream).await.map_err(Error::DrainError)?;
-    let (io, conn) = stream.into_inner();
+    let mut drained = drain_with_timeout(&mut stream, drain).await?;
+    let (mut io, mut conn) = stream.into_inner();
+    let hellos = io.take_hellos();
     let io = io.io;
+    let early_data = take_early_data(&mut conn);
+    let peer_closed = drain_close_notify(conn.reader(), &mut drained, drain.max_len)?;
 
-    setup_inner(io.as_raw_fd(), Connection::Server(conn))?;
-    Ok(KtlsStream::new(io, drained))
+    let info =
+        ConnectionInfo::from_server(&conn, &hellos).with_early_data_accepted(early_data.is_some());
+    let conn = Connection::Server(conn);
+    let keying_material = ExportedKeyingMaterial::export(&conn, exports)?;
+    setup_inner(io.as_fd().as_raw_fd(), conn)?;
+    let mut stream = KtlsStream::new(io, drained)
+        .with_data_before_drained(early_data.unwrap_or_default())
+        .with_connection_info(info)
+        .with_keying_material(keying_material);
+    if peer_closed {
+        stream.mark_read_closed();
+    }
+    Ok(stream)
+}
+
+/// The 0-RTT data rustls accepted (see `ServerConfig::max_early_data_size`),
+/// which it keeps apart from the rest: it comes first in the offloaded stream.
+/// `None` if early data wasn't accepted.
+fn take_early_data(conn: &mut rustls::ServerConnection) -> Option<Vec<u8>> {
+    let mut early_data = vec![];
+    conn.early_data()?.read_to_end(&mut early_data).ok()?;
+    Some(early_data)
 }
 
 /// Configure kTLS for this socket. If this call succeeds, data can be
@@ -224,28 +735,335 @@ where
 /// The inner IO type must be wrapped in [CorkStream] since it's the only way
 /// to drain a rustls stream cleanly. See its documentation for details.
 pub async fn config_ktls_client<IO>(
+    stream: tokio_rustls::client::TlsStream<CorkStream<IO>>,
+) -> Result<KtlsStream<IO>, Error>
+where
+    IO: AsFd + AsyncRead + AsyncWrite + Unpin,
+{
+    config_ktls_client_inner(stream, DrainOptions::default(), &[]).await
+}
+
+/// Like [config_ktls_client], with the options in `config`
+pub async fn config_ktls_client_with_config<IO>(
     mut stream: tokio_rustls::client::TlsStream<CorkStream<IO>>,
+    config: &KtlsConfig,
 ) -> Result<KtlsStream<IO>, Error>
 where
-    IO: AsRawFd + AsyncRead + AsyncWrite + Unpin,
+    IO: AsFd + AsyncRead + AsyncWrite + Unpin,
 {
+    check_config(stream.get_ref().1, config)?;
+    let mut drain = config.drain;
+    let read = match &config.session_tickets {
+        Some(tickets) => {
+            let read = wait_for_tickets(&mut stream, tickets, drain.max_len).await?;
+            drain.max_len = drain.max_len.map(|max_len| max_len - read.len());
+            read
+        }
+        None => vec![],
+    };
+    let stream = config_ktls_client_inner(stream, drain, &config.exports).await?;
+    Ok(stream
+        .with_data_before_drained(read)
+        .with_shutdown_mode(config.shutdown_mode))
+}
+
+/// Reads through rustls until the session tickets asked for arrived or their
+/// timeout elapsed, see [KtlsConfig::with_session_tickets]. Returns the
+/// application data that came in meanwhile, `max_len` bytes at most.
+async fn wait_for_tickets<IO>(
+    stream: &mut tokio_rustls::client::TlsStream<CorkStream<IO>>,
+    tickets: &SessionTickets,
+    max_len: Option<usize>,
+) -> Result<Vec<u8>, Error>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(server_name) = connection_info::sent_server_name(stream.get_ref().0.hellos()) else {
+        tracing::debug!("no SNI to look session tickets up by, offloading right away");
+        return Ok(vec![]);
+    };
+    let counter = &tickets.counter;
+    let start = counter.tls13_tickets_received(&server_name);
+    let arrived = || counter.tls13_tickets_received(&server_name) - start >= tickets.count;
+    let max_len = max_len.unwrap_or(usize::MAX);
+
+    // application data that comes in while we wait has to be handed over too
+    let mut read = vec![];
+    let wait = async {
+        let mut buf = [0u8; 4096];
+        while !arrived() {
+            let n = futures::future::poll_fn(|cx| {
+                let mut buf = ReadBuf::new(&mut buf);
+                match Pin::new(&mut *stream).poll_read(cx, &mut buf) {
+                    Poll::Ready(res) => Poll::Ready(res.map(|()| buf.filled().len())),
+                    // tickets don't make reads return, check after each wakeup
+                    Poll::Pending if arrived() => Poll::Ready(Ok(0)),
+                    Poll::Pending => Poll::Pending,
+                }
+            })
+            .await
+            .map_err(Error::DrainError)?;
+            if n == 0 {
+                break;
+            }
+            if read.len() + n > max_len {
+                return Err(Error::DrainLimitExceeded(max_len));
+            }
+            read.extend_from_slice(&buf[..n]);
+        }
+        Ok::<_, Error>(())
+    };
+    match tokio::time::timeout(tickets.timeout, wait).await {
+        Ok(res) => res?,
+        Err(_) => tracing::debug!(
+            tickets = %tickets.count,
+            "timed out waiting for session tickets, offloading anyway"
+        ),
+    }
+    Ok(read)
+}
+
+/// Like [config_ktls_client_with_config], but gives the rustls stream back in
+/// [OffloadError] when the offload can't even start (see
+/// [config_ktls_server_or_return]).
+pub async fn config_ktls_client_or_return<IO>(
+    stream: tokio_rustls::client::TlsStream<CorkStream<IO>>,
+    config: &KtlsConfig,
+) -> Result<KtlsStream<IO>, OffloadError<tokio_rustls::client::TlsStream<CorkStream<IO>>>>
+where
+    IO: AsFd + AsyncRead + AsyncWrite + Unpin,
+{
+    let (io, conn) = stream.get_ref();
+    if let Err(error) = check_offloadable(io.as_fd().as_raw_fd(), conn, config) {
+        return Err(OffloadError {
+            error,
+            stream: Some(stream),
+        });
+    }
+
+    config_ktls_client_with_config(stream, config)
+        .await
+        .map_err(|error| OffloadError {
+            error,
+            stream: None,
+        })
+}
+
+/// Like [config_ktls_client_or_return], but keeps the connection on rustls
+/// when the offload can't start (see [config_ktls_server_or_fallback]).
+pub async fn config_ktls_client_or_fallback<IO>(
+    stream: tokio_rustls::client::TlsStream<CorkStream<IO>>,
+    config: &KtlsConfig,
+) -> Result<MaybeKtlsStream<IO>, Error>
+where
+    IO: AsFd + AsyncRead + AsyncWrite + Unpin,
+{
+    match config_ktls_client_or_return(stream, config).await {
+        Ok(stream) => Ok(MaybeKtlsStream::Ktls(stream)),
+        Err(OffloadError {
+            error,
+            stream: Some(stream),
+        }) => {
+            tracing::debug!(%error, "can't offload, staying in userspace");
+            Ok(MaybeKtlsStream::Rustls(Box::new(stream.into())))
+        }
+        Err(OffloadError { error, .. }) => Err(error),
+    }
+}
+
+async fn config_ktls_client_inner<IO>(
+    mut stream: tokio_rustls::client::TlsStream<CorkStream<IO>>,
+    drain: DrainOptions,
+    exports: &[KeyingMaterialExport],
+) -> Result<KtlsStream<IO>, Error>
+where
+    IO: AsFd + AsyncRead + AsyncWrite + Unpin,
+{
+    // with `TlsConnector::early_data`, the handshake may still be going on
+    // (0-RTT data is written in the meantime): flushing completes it, and
+    // resends the early data if the server rejected it
+    if stream.get_ref().1.is_handshaking() {
+        stream.flush().await.map_err(Error::HandshakeError)?;
+    }
+
     stream.get_mut().0.corked = true;
-    let drained = drain(&mut stream).await.map_err(Error::DrainError)?;
-    let (io, conn) = stream.into_inner();
+    let mut drained = drain_with_timeout(&mut stream, drain).await?;
+    let (mut io, mut conn) = stream.into_inner();
+    let hellos = io.take_hellos();
     let io = io.io;
+    let peer_closed = drain_close_notify(conn.reader(), &mut drained, drain.max_len)?;
+
+    let info = ConnectionInfo::from_client(&conn, &hellos);
+    let conn = Connection::Client(conn);
+    let keying_material = ExportedKeyingMaterial::export(&conn, exports)?;
+    setup_inner(io.as_fd().as_raw_fd(), conn)?;
+    let mut stream = KtlsStream::new(io, drained)
+        .with_connection_info(info)
+        .with_keying_material(keying_material);
+    if peer_closed {
+        stream.mark_read_closed();
+    }
+    Ok(stream)
+}
+
+/// Configure kTLS for this socket from secrets extracted beforehand, e.g. by
+/// driving a rustls `Connection` by hand and calling `dangerous_extract_secrets`
+/// on it. Nothing may have been read from or written to the socket since: any
+/// record the peer sent after the handshake must have been processed by
+/// whoever extracted the secrets, or the sequence numbers won't match.
+///
+/// `version` must be the one `cipher_suite` belongs to.
+pub fn config_ktls_from_secrets<IO>(
+    stream: IO,
+    secrets: ExtractedSecrets,
+    version: rustls::ProtocolVersion,
+    cipher_suite: SupportedCipherSuite,
+) -> Result<KtlsStream<IO>, Error>
+where
+    IO: AsFd,
+{
+    if !offload_enabled() {
+        return Err(Error::OffloadDisabled);
+    }
+    if cipher_suite.version().version != version {
+        return Err(Error::VersionMismatch {
+            version,
+            cipher_suite,
+        });
+    }
+
+    let fd = stream.as_fd().as_raw_fd();
+    if is_ktls_enabled_fd(fd).map_err(Error::CryptoInfoQuery)? {
+        return Err(Error::AlreadyOffloaded);
+    }
+    setup_secrets(fd, cipher_suite, secrets)?;
+    Ok(KtlsStream::new(stream, None))
+}
+
+/// Configure kTLS from the pieces of a plain rustls server connection, for
+/// users that drive rustls on their own I/O (e.g. with `rustls::Stream`)
+/// instead of going through tokio-rustls.
+///
+/// The handshake must be complete, and everything rustls has to send written
+/// to `io` already (fails with [Error::PendingWrites] otherwise). Records
+/// passed to `read_tls` are processed here, and their plaintext comes first
+/// in the offloaded stream; only a partial record would be lost, so don't
+/// call `read_tls` after the handshake unless you process what it got.
+pub fn config_ktls_server_parts<IO>(
+    io: IO,
+    mut conn: rustls::ServerConnection,
+) -> Result<KtlsStream<IO>, Error>
+where
+    IO: AsFd,
+{
+    check_parts(&mut conn)?;
+    let early_data = take_early_data(&mut conn);
+    let mut drained = None;
+    let peer_closed = drain_close_notify(conn.reader(), &mut drained, None)?;
+
+    let info = ConnectionInfo::from_server(&conn, &Hellos::default())
+        .with_early_data_accepted(early_data.is_some());
+    setup_inner(io.as_fd().as_raw_fd(), Connection::Server(conn))?;
+    let mut stream = KtlsStream::new(io, drained)
+        .with_data_before_drained(early_data.unwrap_or_default())
+        .with_connection_info(info);
+    if peer_closed {
+        stream.mark_read_closed();
+    }
+    Ok(stream)
+}
+
+/// The client side of [config_ktls_server_parts]
+pub fn config_ktls_client_parts<IO>(
+    io: IO,
+    mut conn: rustls::ClientConnection,
+) -> Result<KtlsStream<IO>, Error>
+where
+    IO: AsFd,
+{
+    check_parts(&mut conn)?;
+    let mut drained = None;
+    let peer_closed = drain_close_notify(conn.reader(), &mut drained, None)?;
 
-    setup_inner(io.as_raw_fd(), Connection::Client(conn))?;
-    Ok(KtlsStream::new(io, drained))
+    let info = ConnectionInfo::from_client(&conn, &Hellos::default());
+    setup_inner(io.as_fd().as_raw_fd(), Connection::Client(conn))?;
+    let mut stream = KtlsStream::new(io, drained).with_connection_info(info);
+    if peer_closed {
+        stream.mark_read_closed();
+    }
+    Ok(stream)
 }
 
+fn check_parts<Data>(conn: &mut rustls::ConnectionCommon<Data>) -> Result<(), Error> {
+    if !offload_enabled() {
+        return Err(Error::OffloadDisabled);
+    }
+    if conn.is_handshaking() {
+        return Err(Error::NoNegotiatedCipherSuite);
+    }
+    if conn.wants_write() {
+        return Err(Error::PendingWrites);
+    }
+    conn.process_new_packets()
+        .map_err(|err| Error::DrainError(io::Error::new(io::ErrorKind::InvalidData, err)))?;
+    // processing may have queued an alert
+    if conn.wants_write() {
+        return Err(Error::PendingWrites);
+    }
+    Ok(())
+}
+
+/// How the rustls stream is drained before offloading it
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct DrainOptions {
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) max_len: Option<usize>,
+    pub(crate) skip: bool,
+}
+
+async fn drain_with_timeout(
+    stream: &mut (impl AsyncRead + Unpin),
+    options: DrainOptions,
+) -> Result<Option<Vec<u8>>, Error> {
+    if options.skip {
+        return Ok(None);
+    }
+
+    let max_len = options.max_len.unwrap_or(usize::MAX);
+    let Some(timeout) = options.timeout else {
+        return drain(stream, max_len).await;
+    };
+
+    match tokio::time::timeout(timeout, drain(stream, max_len)).await {
+        Ok(res) => res,
+        Err(_) => Err(Error::DrainTimedOut(timeout)),
+    }
+}
+
+/// How much is buffered at first while draining, grown as needed
+const DRAIN_BUFFER_SIZE: usize = 128 * 1024;
+
 /// Read all the bytes we can read without blocking. This is used to drained the
 /// already-decrypted buffer from a tokio-rustls I/O type
-async fn drain(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<Vec<u8>>> {
+async fn drain(
+    stream: &mut (impl AsyncRead + Unpin),
+    max_len: usize,
+) -> Result<Option<Vec<u8>>, Error> {
     tracing::trace!("Draining rustls stream");
-    let mut drained = vec![0u8; 128 * 1024];
+    // one more byte than allowed, to tell "exactly at the limit" from "over it"
+    let buf_limit = max_len.saturating_add(1);
+    let mut drained = vec![0u8; DRAIN_BUFFER_SIZE.min(buf_limit)];
     let mut filled = 0;
 
     loop {
+        if filled == drained.len() {
+            if filled > max_len {
+                tracing::debug!("Drained more than {max_len} bytes, giving up");
+                return Err(Error::DrainLimitExceeded(max_len));
+            }
+            drained.resize(filled.saturating_mul(2).min(buf_limit), 0);
+        }
+
         tracing::trace!("stream.read called");
         let n = match stream.read(&mut drained[filled..]).await {
             Ok(n) => n,
@@ -256,7 +1074,7 @@ async fn drain(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<
             }
             Err(e) => {
                 tracing::trace!("stream.read returned error: {e}");
-                return Err(e);
+                return Err(Error::DrainError(e));
             }
         };
         tracing::trace!("stream.read returned {n}");
@@ -267,17 +1085,106 @@ async fn drain(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Option<
         filled += n;
     }
 
+    if filled > max_len {
+        return Err(Error::DrainLimitExceeded(max_len));
+    }
+
     let maybe_drained = if filled == 0 {
         None
     } else {
         tracing::trace!("Draining rustls stream done: drained {filled} bytes");
-        drained.resize(filled, 0);
+        drained.truncate(filled);
+        // it's kept until read, don't hold on to the whole drain buffer
+        // for a few bytes
+        drained.shrink_to_fit();
         Some(drained)
     };
     Ok(maybe_drained)
 }
 
+/// Whether the peer sent a close_notify before the offload (health checkers
+/// do that right after the handshake), picking up whatever plaintext rustls
+/// still holds on the way, within `max_len` in total. The drain can't tell:
+/// rustls reports a close_notify as EOF, the same way [CorkStream] reports a
+/// record boundary.
+fn drain_close_notify(
+    mut reader: rustls::Reader<'_>,
+    drained: &mut Option<Vec<u8>>,
+    max_len: Option<usize>,
+) -> Result<bool, Error> {
+    let max_len = max_len.unwrap_or(usize::MAX);
+    let mut buf = [0u8; 4096];
+    loop {
+        match reader.read(&mut buf) {
+            // a clean EOF is only reported once the peer sent close_notify
+            Ok(0) => return Ok(true),
+            Ok(n) => {
+                let drained = drained.get_or_insert_with(Vec::new);
+                if drained.len() + n > max_len {
+                    return Err(Error::DrainLimitExceeded(max_len));
+                }
+                drained.extend_from_slice(&buf[..n]);
+            }
+            // WouldBlock if the session is still open, UnexpectedEof if the
+            // peer hung up without a close_notify: the kernel will see that too
+            Err(_) => return Ok(false),
+        }
+    }
+}
+
+/// What rustls says when `enable_secret_extraction` wasn't set, it has no
+/// dedicated error variant for it
+const SECRET_EXTRACTION_DISABLED: &str = "Secret extraction is disabled";
+
+/// The checks that can fail without consuming anything from the connection.
+/// Attaching the ULP doesn't change how the socket behaves until keys are
+/// installed, so it's done here too. Whether secret extraction is enabled
+/// can't be told from the connection, only from its config.
+fn check_offloadable(
+    fd: RawFd,
+    conn: &rustls::CommonState,
+    config: &KtlsConfig,
+) -> Result<(), Error> {
+    if !config.offload_enabled() {
+        return Err(Error::OffloadDisabled);
+    }
+    check_config(conn, config)?;
+    if is_ktls_enabled_fd(fd).map_err(Error::CryptoInfoQuery)? {
+        return Err(Error::AlreadyOffloaded);
+    }
+    // a 0-RTT client only learns it once the handshake is completed
+    if !conn.is_handshaking() && conn.negotiated_cipher_suite().is_none() {
+        return Err(Error::NoNegotiatedCipherSuite);
+    }
+    if let Some(cipher_suite) = conn.negotiated_cipher_suite() {
+        let ciphers = match &config.compatible_ciphers {
+            Some(ciphers) => Some(&**ciphers),
+            None => compatible_ciphers(),
+        };
+        // if the kernel couldn't be probed, installing the keys will tell
+        if ciphers.is_some_and(|ciphers| !ciphers.is_compatible(&cipher_suite)) {
+            return Err(KtlsCompatibilityError::UnsupportedCipherSuite(cipher_suite).into());
+        }
+    }
+    ensure_ulp(fd)
+}
+
+/// What `config` rules out, whatever the socket
+fn check_config(conn: &rustls::CommonState, config: &KtlsConfig) -> Result<(), Error> {
+    if config.rekey_policy == RekeyPolicy::Userspace
+        && conn.protocol_version() == Some(rustls::ProtocolVersion::TLSv1_3)
+    {
+        return Err(Error::RekeyUnsupported);
+    }
+    Ok(())
+}
+
 fn setup_inner(fd: RawFd, conn: Connection) -> Result<(), Error> {
+    // the kernel would say EEXIST, which looks like any other failure
+    if is_ktls_enabled_fd(fd).map_err(Error::CryptoInfoQuery)? {
+        return Err(Error::AlreadyOffloaded);
+    }
+
     let cipher_suite = match conn.negotiated_cipher_suite() {
         Some(cipher_suite) => cipher_suite,
         None => {
@@ -287,16 +1194,104 @@ fn setup_inner(fd: RawFd, conn: Connection) -> Result<(), Error> {
 
     let secrets = match conn.extract_secrets() {
         Ok(secrets) => secrets,
+        Err(rustls::Error::General(msg)) if msg == SECRET_EXTRACTION_DISABLED => {
+            return Err(Error::SecretExtractionDisabled)
+        }
         Err(err) => return Err(Error::ExportSecrets(err)),
     };
 
-    ffi::setup_ulp(fd).map_err(Error::UlpError)?;
+    setup_secrets(fd, cipher_suite, secrets)
+}
+
+fn setup_secrets(
+    fd: RawFd,
+    cipher_suite: SupportedCipherSuite,
+    secrets: ExtractedSecrets,
+) -> Result<(), Error> {
+    ensure_ulp(fd)?;
+
+    let tx = crypto_info(cipher_suite, Direction::Tx, secrets.tx)?;
+    setup_tls_info(fd, Direction::Tx, tx)?;
+
+    let rx = crypto_info(cipher_suite, Direction::Rx, secrets.rx)?;
+    setup_tls_info(fd, Direction::Rx, rx)?;
+
+    Ok(())
+}
 
-    let tx = CryptoInfo::from_rustls(cipher_suite, secrets.tx)?;
-    setup_tls_info(fd, ffi::Direction::Tx, tx)?;
+/// Attach the `tls` upper level protocol to a socket _before_ the TLS
+/// handshake, so that kernels without kTLS support are detected right away
+/// and the connection can go through userspace TLS without wasting a
+/// handshake. Until keys are installed by `config_ktls_*`, the socket behaves
+/// exactly like a plain TCP socket.
+pub fn attach_ulp(io: &impl AsFd) -> Result<(), Error> {
+    ensure_ulp(io.as_fd().as_raw_fd())
+}
 
-    let rx = CryptoInfo::from_rustls(cipher_suite, secrets.rx)?;
-    setup_tls_info(fd, ffi::Direction::Rx, rx)?;
+/// Makes sure the `tls` kernel module is loaded, attaching the ULP to a
+/// scratch loopback socket to have the kernel load it if needed (which takes
+/// CAP_SYS_MODULE unless it's already loaded or built in). Fails with
+/// [Error::UlpNotAvailable] if there is no such module. Once this succeeds,
+/// a failing offload means the cipher or TLS version isn't supported
+/// ([Error::Setsockopt]), see [CompatibleCiphers].
+pub fn ensure_tls_module() -> Result<(), Error> {
+    if tls_module_loaded() {
+        return Ok(());
+    }
 
+    let sock = probe::self_connected_socket().map_err(Error::UlpError)?;
+    ensure_ulp(sock.as_fd().as_raw_fd())?;
+    tracing::debug!("loaded the tls kernel module");
     Ok(())
 }
+
+fn tls_module_loaded() -> bool {
+    // lists built-in ULPs too, unlike /sys/module
+    std::fs::read_to_string("/proc/sys/net/ipv4/tcp_available_ulp")
+        .is_ok_and(|ulps| ulps.split_whitespace().any(|ulp| ulp == "tls"))
+}
+
+fn ensure_ulp(fd: RawFd) -> Result<(), Error> {
+    match ffi::setup_ulp(fd) {
+        Ok(()) => Ok(()),
+        Err(e) if e.raw_os_error() == Some(libc::EEXIST) => {
+            // attached early through `attach_ulp`
+            if ffi::has_tls_ulp(fd).unwrap_or(false) {
+                Ok(())
+            } else {
+                Err(Error::OtherUlpAttached)
+            }
+        }
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Err(Error::UlpNotAvailable),
+        Err(e) => Err(Error::UlpError(e)),
+    }
+}
+
+/// Returns true if kTLS is set up on this socket: the `tls` ULP is attached
+/// and keys are installed, e.g. by an earlier `config_ktls_*` call. Attaching
+/// the ULP alone (see [attach_ulp]) doesn't count.
+pub fn is_ktls_enabled(io: &impl AsFd) -> io::Result<bool> {
+    is_ktls_enabled_fd(io.as_fd().as_raw_fd())
+}
+
+fn is_ktls_enabled_fd(fd: RawFd) -> io::Result<bool> {
+    if !ffi::has_tls_ulp(fd)? {
+        return Ok(false);
+    }
+    Ok(ffi::get_crypto_info(fd, Direction::Tx)?.is_some()
+        || ffi::get_crypto_info(fd, Direction::Rx)?.is_some())
+}
+
+fn crypto_info(
+    cipher_suite: SupportedCipherSuite,
+    direction: Direction,
+    secrets: (u64, ConnectionTrafficSecrets),
+) -> Result<CryptoInfo, Error> {
+    CryptoInfo::from_rustls(cipher_suite, secrets).map_err(|err| match err {
+        KtlsCompatibilityError::UnsupportedCipherSuite(cipher_suite) => Error::UnsupportedSecrets {
+            cipher_suite,
+            direction,
+        },
+        err => err.into(),
+    })
+}
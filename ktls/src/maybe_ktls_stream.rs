@@ -0,0 +1,138 @@
+use std::{
+    io::{self, IoSlice},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    pin::Pin,
+    task,
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::TlsStream;
+
+use crate::{AcceptedStream, AsyncReadReady, CorkStream, KtlsStream};
+
+/// Either an offloaded stream, or the rustls stream the connection stayed on,
+/// as returned by [crate::config_ktls_server_or_fallback],
+/// [crate::config_ktls_client_or_fallback] and [crate::KtlsConnector]. Reads
+/// and writes go to whichever it is, so the same code serves both.
+// Like [AcceptedStream], the offloaded stream stays inline and rustls's
+// larger state is boxed
+#[allow(clippy::large_enum_variant)]
+pub enum MaybeKtlsStream<IO>
+where
+    IO: AsFd,
+{
+    Ktls(KtlsStream<IO>),
+    Rustls(Box<TlsStream<CorkStream<IO>>>),
+}
+
+impl<IO> MaybeKtlsStream<IO>
+where
+    IO: AsFd,
+{
+    pub fn is_offloaded(&self) -> bool {
+        matches!(self, MaybeKtlsStream::Ktls(_))
+    }
+}
+
+impl<IO> From<AcceptedStream<IO>> for MaybeKtlsStream<IO>
+where
+    IO: AsFd,
+{
+    fn from(stream: AcceptedStream<IO>) -> Self {
+        match stream {
+            AcceptedStream::Ktls(stream) => MaybeKtlsStream::Ktls(stream),
+            AcceptedStream::Rustls(stream) => MaybeKtlsStream::Rustls(Box::new((*stream).into())),
+        }
+    }
+}
+
+impl<IO> AsRawFd for MaybeKtlsStream<IO>
+where
+    IO: AsFd,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            MaybeKtlsStream::Ktls(stream) => stream.as_raw_fd(),
+            MaybeKtlsStream::Rustls(stream) => stream.get_ref().0.io.as_fd().as_raw_fd(),
+        }
+    }
+}
+
+impl<IO> AsFd for MaybeKtlsStream<IO>
+where
+    IO: AsFd,
+{
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            MaybeKtlsStream::Ktls(stream) => stream.as_fd(),
+            MaybeKtlsStream::Rustls(stream) => stream.get_ref().0.io.as_fd(),
+        }
+    }
+}
+
+impl<IO> AsyncRead for MaybeKtlsStream<IO>
+where
+    IO: AsFd + AsyncRead + AsyncReadReady + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeKtlsStream::Ktls(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeKtlsStream::Rustls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<IO> AsyncWrite for MaybeKtlsStream<IO>
+where
+    IO: AsFd + AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> task::Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeKtlsStream::Ktls(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeKtlsStream::Rustls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> task::Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeKtlsStream::Ktls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            MaybeKtlsStream::Rustls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            MaybeKtlsStream::Ktls(stream) => stream.is_write_vectored(),
+            MaybeKtlsStream::Rustls(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeKtlsStream::Ktls(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeKtlsStream::Rustls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeKtlsStream::Ktls(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeKtlsStream::Rustls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
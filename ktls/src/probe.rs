@@ -0,0 +1,100 @@
+use std::{ffi::CStr, io, net::SocketAddr, os::fd::AsRawFd};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{
+    ffi, CompatibleCiphers, CompatibleCiphersForVersion, CompatibleDirections, CompatibleVersions,
+};
+
+/// How [CompatibleCiphers::new_with] finds out what the kernel supports
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProbeStrategy {
+    /// Connections to a loopback listener, like [CompatibleCiphers::new_sync]
+    #[default]
+    Listener,
+    /// Loopback sockets connected to themselves (a TCP simultaneous open),
+    /// one per probe: no listener is bound, for sandboxes that forbid it.
+    SelfConnect,
+    /// Infers support from the kernel version, and only confirms that the
+    /// `tls` ULP can be attached, on a single self-connected socket. Cheapest,
+    /// but misses backports and modules built without some ciphers.
+    KernelVersion,
+}
+
+impl CompatibleCiphers {
+    /// Probes the kernel with the given strategy, with blocking sockets
+    pub fn new_with(strategy: ProbeStrategy) -> io::Result<Self> {
+        match strategy {
+            ProbeStrategy::Listener => Self::new_sync(),
+            ProbeStrategy::SelfConnect => {
+                let socks = (0..Self::PROBES_COUNT)
+                    .map(|_| self_connected_socket())
+                    .collect::<io::Result<Vec<_>>>()?;
+
+                let mut ciphers = CompatibleCiphers::default();
+                ciphers.probe(&socks);
+                Ok(ciphers)
+            }
+            ProbeStrategy::KernelVersion => {
+                let sock = self_connected_socket()?;
+                if ffi::setup_ulp(sock.as_raw_fd()).is_err() {
+                    return Ok(CompatibleCiphers::default());
+                }
+                Ok(Self::from_kernel_version(kernel_version()?))
+            }
+        }
+    }
+
+    /// What mainline kernels support, by the version each feature landed in
+    fn from_kernel_version(version: (u32, u32)) -> Self {
+        let directions = |tx: (u32, u32), rx: (u32, u32)| CompatibleDirections {
+            tx: version >= tx,
+            rx: version >= rx,
+        };
+
+        CompatibleCiphers {
+            tls12: CompatibleCiphersForVersion {
+                aes_gcm_128: directions((4, 13), (4, 17)),
+                aes_gcm_256: directions((5, 1), (5, 1)),
+                chacha20_poly1305: directions((5, 11), (5, 11)),
+            },
+            tls13: CompatibleCiphersForVersion {
+                aes_gcm_128: directions((5, 1), (5, 2)),
+                aes_gcm_256: directions((5, 1), (5, 2)),
+                chacha20_poly1305: directions((5, 11), (5, 11)),
+            },
+            versions: CompatibleVersions {
+                tls12: directions((4, 13), (4, 17)),
+                tls13: directions((5, 1), (5, 2)),
+            },
+        }
+    }
+}
+
+pub(crate) fn self_connected_socket() -> io::Result<Socket> {
+    let sock = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+    sock.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into())?;
+    let addr = sock.local_addr()?;
+    sock.connect(&addr)?;
+    Ok(sock)
+}
+
+/// The running kernel's `(major, minor)` version, from `uname(2)`
+pub(crate) fn kernel_version() -> io::Result<(u32, u32)> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) }.to_string_lossy();
+    let mut parts = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse::<u32>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => Ok((major, minor)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected kernel release: {release}"),
+        )),
+    }
+}
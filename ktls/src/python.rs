@@ -0,0 +1,110 @@
+//! Python bindings, for asyncio servers that terminate TLS with their own
+//! stack and want to enable kTLS on their sockets. Build with:
+//!
+//! ```text
+//! cargo rustc --release --features python --crate-type cdylib
+//! ```
+//!
+//! and rename the resulting `libktls.so` to `ktls.so`.
+
+use std::{collections::HashMap, os::unix::prelude::RawFd};
+
+use pyo3::{exceptions::PyValueError, prelude::*};
+
+use crate::{
+    ffi::{self, CryptoInfo, Direction},
+    CompatibleCiphers, CompatibleCiphersForVersion, Error,
+};
+
+fn to_py_err(err: Error) -> PyErr {
+    match err {
+        Error::UlpError(e) | Error::Setsockopt { source: e, .. } => e.into(),
+        err => PyValueError::new_err(err.to_string()),
+    }
+}
+
+/// Probe the kernel and return which ciphers can be offloaded, as
+/// `{"tls12": {"aes_gcm_128": bool, ...}, "tls13": {...}}`.
+#[pyfunction]
+fn compatible_ciphers() -> PyResult<HashMap<&'static str, HashMap<&'static str, bool>>> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+    let ciphers = rt.block_on(CompatibleCiphers::new())?;
+
+    let for_version = |c: &CompatibleCiphersForVersion| {
+        HashMap::from([
+            ("aes_gcm_128", c.aes_gcm_128.both()),
+            ("aes_gcm_256", c.aes_gcm_256.both()),
+            ("chacha20_poly1305", c.chacha20_poly1305.both()),
+        ])
+    };
+    Ok(HashMap::from([
+        ("tls12", for_version(&ciphers.tls12)),
+        ("tls13", for_version(&ciphers.tls13)),
+    ]))
+}
+
+/// Enable the `tls` upper level protocol on `fd`.
+#[pyfunction]
+fn setup_ulp(fd: RawFd) -> PyResult<()> {
+    ffi::setup_ulp(fd).map_err(|e| to_py_err(Error::UlpError(e)))
+}
+
+/// Install the transmit keys on `fd`. `cipher` is one of the kernel's
+/// `TLS_CIPHER_*` constants, `version` e.g. `0x0304` for TLS 1.3.
+#[pyfunction]
+#[pyo3(signature = (fd, version, cipher, key, iv, salt, seq))]
+#[allow(clippy::too_many_arguments)]
+fn setup_tx(
+    fd: RawFd,
+    version: u16,
+    cipher: u16,
+    key: &[u8],
+    iv: &[u8],
+    salt: &[u8],
+    seq: u64,
+) -> PyResult<()> {
+    setup(fd, Direction::Tx, version, cipher, key, iv, salt, seq)
+}
+
+/// Install the receive keys on `fd`, see `setup_tx`.
+#[pyfunction]
+#[pyo3(signature = (fd, version, cipher, key, iv, salt, seq))]
+#[allow(clippy::too_many_arguments)]
+fn setup_rx(
+    fd: RawFd,
+    version: u16,
+    cipher: u16,
+    key: &[u8],
+    iv: &[u8],
+    salt: &[u8],
+    seq: u64,
+) -> PyResult<()> {
+    setup(fd, Direction::Rx, version, cipher, key, iv, salt, seq)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn setup(
+    fd: RawFd,
+    dir: Direction,
+    version: u16,
+    cipher: u16,
+    key: &[u8],
+    iv: &[u8],
+    salt: &[u8],
+    seq: u64,
+) -> PyResult<()> {
+    let info = CryptoInfo::from_raw(version, cipher, key, iv, salt, seq)
+        .map_err(|e| to_py_err(e.into()))?;
+    ffi::setup_tls_info(fd, dir, info).map_err(to_py_err)
+}
+
+#[pymodule]
+fn ktls(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(compatible_ciphers, m)?)?;
+    m.add_function(wrap_pyfunction!(setup_ulp, m)?)?;
+    m.add_function(wrap_pyfunction!(setup_tx, m)?)?;
+    m.add_function(wrap_pyfunction!(setup_rx, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,9 @@
+//! The kernel interface on its own, for TLS stacks other than rustls: once
+//! the handshake is done, attach the `tls` ULP with [setup_ulp], then install
+//! a [CryptoInfo] per direction with [setup_tls_info]. Nothing may be read
+//! from or written to the socket in between.
+
+pub use crate::ffi::{
+    setup_tls_info, setup_ulp, CryptoInfo, Direction, KtlsCompatibilityError,
+    TLS_1_2_VERSION_NUMBER, TLS_1_3_VERSION_NUMBER,
+};
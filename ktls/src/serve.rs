@@ -0,0 +1,55 @@
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
+
+use rustls::ServerConfig;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{KtlsAcceptor, MaybeKtlsStream};
+
+/// How long to back off when `accept` fails, e.g. with EMFILE: retrying right
+/// away would spin until a connection closes.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Runs the accept loop on `listener`: every connection gets its handshake
+/// and offload on its own task, then goes to `handler`. Failures are logged
+/// and the loop goes on, it never returns.
+///
+/// The handler gets a [MaybeKtlsStream] rather than a [crate::KtlsStream] so
+/// that turning offload off (see [crate::set_enabled]) keeps serving
+/// connections over rustls instead of dropping them.
+pub async fn serve<F, Fut>(listener: TcpListener, config: Arc<ServerConfig>, handler: F)
+where
+    F: Fn(MaybeKtlsStream<TcpStream>, SocketAddr) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    serve_with(listener, KtlsAcceptor::new(config), handler).await
+}
+
+/// Like [serve], with a configured [KtlsAcceptor] (offload policy, timeout...)
+pub async fn serve_with<F, Fut>(listener: TcpListener, acceptor: KtlsAcceptor, handler: F)
+where
+    F: Fn(MaybeKtlsStream<TcpStream>, SocketAddr) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let acceptor = Arc::new(acceptor);
+    let handler = Arc::new(handler);
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::warn!(%e, "accept failed");
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            match acceptor.accept(stream).await {
+                Ok(stream) => handler(stream.into(), addr).await,
+                Err(e) => tracing::debug!(%e, %addr, "handshake or offload failed"),
+            }
+        });
+    }
+}
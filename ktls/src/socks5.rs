@@ -0,0 +1,216 @@
+//! Minimal SOCKS5 client (RFC 1928, with RFC 1929 username/password auth),
+//! to reach the upstream through a proxy before handshaking and offloading,
+//! see [crate::KtlsConnector::with_socks5_proxy] and
+//! [crate::connect_via_socks5], or by hand:
+//!
+//! ```no_run
+//! # async fn f(connector: tokio_rustls::TlsConnector) -> Result<(), Box<dyn std::error::Error>> {
+//! let tcp = ktls::socks5::connect("127.0.0.1:1080", "example.com", 443, None).await?;
+//! let tls = connector
+//!     .connect("example.com".try_into()?, ktls::CorkStream::new(tcp))
+//!     .await?;
+//! let stream = ktls::config_ktls_client(tls).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{io, net::IpAddr};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// A SOCKS5 proxy, and the credentials to use it with
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    addr: String,
+    credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// The proxy at `addr` (`host:port`), without authentication
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            credentials: None,
+        }
+    }
+
+    /// Authenticate with a username and a password (RFC 1929)
+    pub fn with_credentials(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    /// See [connect]
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let credentials = self
+            .credentials
+            .as_ref()
+            .map(|(username, password)| (username.as_str(), password.as_str()));
+        connect(self.addr.as_str(), host, port, credentials).await
+    }
+}
+
+/// Connect to `host:port` through the SOCKS5 proxy at `proxy`. `host` can be
+/// an IP address or a domain name, in which case it's resolved by the proxy.
+/// `credentials` is an optional `(username, password)` pair.
+pub async fn connect(
+    proxy: impl ToSocketAddrs,
+    host: &str,
+    port: u16,
+    credentials: Option<(&str, &str)>,
+) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    negotiate_auth(&mut stream, credentials).await?;
+    request_connect(&mut stream, host, port).await?;
+
+    Ok(stream)
+}
+
+async fn negotiate_auth(
+    stream: &mut TcpStream,
+    credentials: Option<(&str, &str)>,
+) -> io::Result<()> {
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[VERSION, 2, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD],
+        None => &[VERSION, 1, METHOD_NO_AUTH],
+    };
+    stream.write_all(greeting).await?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(invalid_data("proxy isn't speaking SOCKS5"));
+    }
+
+    match (reply[1], credentials) {
+        (METHOD_NO_AUTH, _) => Ok(()),
+        (METHOD_USERNAME_PASSWORD, Some((username, password))) => {
+            let mut req = vec![AUTH_VERSION];
+            push_len_prefixed(&mut req, username.as_bytes())?;
+            push_len_prefixed(&mut req, password.as_bytes())?;
+            stream.write_all(&req).await?;
+
+            let mut reply = [0u8; 2];
+            stream.read_exact(&mut reply).await?;
+            if reply[0] != AUTH_VERSION {
+                return Err(invalid_data(
+                    "proxy isn't speaking SOCKS5 username/password authentication",
+                ));
+            }
+            if reply[1] != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "SOCKS5 proxy rejected the credentials",
+                ));
+            }
+            Ok(())
+        }
+        (METHOD_NOT_ACCEPTABLE, _) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5 proxy accepted none of the offered authentication methods",
+        )),
+        (method, _) => Err(invalid_data(format!(
+            "SOCKS5 proxy picked an authentication method we didn't offer: {method:#x}"
+        ))),
+    }
+}
+
+async fn request_connect(stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+    let mut req = vec![VERSION, CMD_CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            req.push(ATYP_IPV4);
+            req.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            req.push(ATYP_IPV6);
+            req.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            req.push(ATYP_DOMAIN);
+            push_len_prefixed(&mut req, host.as_bytes())?;
+        }
+    }
+    req.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&req).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(invalid_data("proxy isn't speaking SOCKS5"));
+    }
+    if reply[1] != 0 {
+        return Err(reply_error(reply[1]));
+    }
+
+    // skip the bound address, we have no use for it
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        atyp => {
+            return Err(invalid_data(format!(
+                "unknown SOCKS5 address type {atyp:#x}"
+            )))
+        }
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    tracing::trace!(%host, %port, "SOCKS5 tunnel established");
+    Ok(())
+}
+
+fn push_len_prefixed(buf: &mut Vec<u8>, data: &[u8]) -> io::Result<()> {
+    let len = u8::try_from(data.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SOCKS5 fields are limited to 255 bytes",
+        )
+    })?;
+    buf.push(len);
+    buf.extend_from_slice(data);
+    Ok(())
+}
+
+fn reply_error(rep: u8) -> io::Error {
+    let (kind, msg) = match rep {
+        0x02 => (
+            io::ErrorKind::PermissionDenied,
+            "connection not allowed by ruleset",
+        ),
+        0x03 => (io::ErrorKind::Other, "network unreachable"),
+        0x04 => (io::ErrorKind::Other, "host unreachable"),
+        0x05 => (io::ErrorKind::ConnectionRefused, "connection refused"),
+        0x06 => (io::ErrorKind::TimedOut, "TTL expired"),
+        0x07 => (io::ErrorKind::Unsupported, "command not supported"),
+        0x08 => (io::ErrorKind::Unsupported, "address type not supported"),
+        _ => (io::ErrorKind::Other, "general SOCKS server failure"),
+    };
+    io::Error::new(kind, format!("SOCKS5 proxy failed to connect: {msg}"))
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
@@ -0,0 +1,413 @@
+use std::{
+    io::{self, IoSlice},
+    ops::Deref,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{tcp, TcpStream},
+};
+
+use crate::{
+    ktls_stream::{
+        poll_flush_with, poll_read_with, poll_shutdown_with, poll_write_vectored_with,
+        poll_write_with, ReadState, WriteState,
+    },
+    stats::StreamStats,
+    AsyncReadReady, ConnectionInfo, Extensions, KtlsStream,
+};
+
+/// A socket shared by both halves of a split [KtlsStream], or by the tasks
+/// using a `&KtlsStream`. Everything tokio needs is available through
+/// `&TcpStream`, so there's no locking.
+pub(crate) struct SharedTcpStream<S> {
+    stream: S,
+}
+
+impl<S> SharedTcpStream<S> {
+    pub(crate) fn new(stream: S) -> Self {
+        Self { stream }
+    }
+}
+
+impl KtlsStream<TcpStream> {
+    /// Splits the stream into an owned read half and an owned write half,
+    /// which can be moved into separate tasks.
+    ///
+    /// The read half keeps the drained data, the connection info and the
+    /// extensions, both halves share the stats. If the read half receives a
+    /// `close_notify` or a fatal alert (and replies with its own
+    /// `close_notify`), writes on the write half return 0, like they would on
+    /// the unsplit stream. Dropping the write half doesn't shut anything
+    /// down: call `shutdown` to send a `close_notify`.
+    pub fn into_split(self) -> (KtlsReadHalf, KtlsWriteHalf) {
+        let (read_half, write_half) = self.split_with(|stream| {
+            let stream = Arc::new(stream);
+            (
+                SharedTcpStream::new(stream.clone()),
+                SharedTcpStream::new(stream),
+            )
+        });
+        let close_notify_sent = Arc::new(AtomicBool::new(false));
+        (
+            KtlsReadHalf {
+                inner: read_half,
+                close_notify_sent: close_notify_sent.clone(),
+            },
+            KtlsWriteHalf {
+                inner: write_half,
+                close_notify_sent,
+            },
+        )
+    }
+}
+
+impl KtlsStream<TcpStream> {
+    /// Splits the stream into a read half and a write half borrowing it, to
+    /// read and write concurrently from a single task. Unlike
+    /// [KtlsStream::into_split], this doesn't allocate.
+    pub fn split(&mut self) -> (KtlsReadHalfRef<'_>, KtlsWriteHalfRef<'_>) {
+        let (stream, read_state, write_state) = self.split_states();
+        let fd = stream.as_raw_fd();
+        let (r, w) = stream.split();
+        (
+            KtlsReadHalfRef {
+                inner: r,
+                fd,
+                state: read_state,
+            },
+            KtlsWriteHalfRef {
+                inner: w,
+                fd,
+                state: write_state,
+            },
+        )
+    }
+}
+
+/// The borrowed read half of a [KtlsStream], see [KtlsStream::split]
+pub struct KtlsReadHalfRef<'a> {
+    inner: tcp::ReadHalf<'a>,
+    fd: RawFd,
+    state: ReadState<'a>,
+}
+
+impl AsyncRead for KtlsReadHalfRef<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        let this = self.get_mut();
+        poll_read_with(Pin::new(&mut this.inner), this.fd, &mut this.state, cx, buf)
+    }
+}
+
+impl AsRawFd for KtlsReadHalfRef<'_> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl AsFd for KtlsReadHalfRef<'_> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_ref().as_fd()
+    }
+}
+
+/// The borrowed write half of a [KtlsStream], see [KtlsStream::split]
+pub struct KtlsWriteHalfRef<'a> {
+    inner: tcp::WriteHalf<'a>,
+    fd: RawFd,
+    state: WriteState<'a>,
+}
+
+impl AsyncWrite for KtlsWriteHalfRef<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> task::Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        poll_write_with(Pin::new(&mut this.inner), &mut this.state, cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> task::Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        poll_write_vectored_with(Pin::new(&mut this.inner), &mut this.state, cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<io::Result<()>> {
+        let this = self.get_mut();
+        poll_flush_with(Pin::new(&mut this.inner), &mut this.state, cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        let this = self.get_mut();
+        poll_shutdown_with(Pin::new(&mut this.inner), this.fd, &mut this.state, cx)
+    }
+}
+
+impl AsRawFd for KtlsWriteHalfRef<'_> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl AsFd for KtlsWriteHalfRef<'_> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_ref().as_fd()
+    }
+}
+
+/// The read half of a [KtlsStream], see [KtlsStream::into_split]
+pub struct KtlsReadHalf {
+    inner: KtlsStream<SharedTcpStream<Arc<TcpStream>>>,
+    close_notify_sent: Arc<AtomicBool>,
+}
+
+impl KtlsReadHalf {
+    /// Returns what's known about the offloaded TLS session
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        self.inner.connection_info()
+    }
+
+    /// Per-connection data attached by the application, see [Extensions]
+    pub fn extensions(&self) -> &Extensions {
+        self.inner.extensions()
+    }
+
+    /// Mutable access to the per-connection data, see [Extensions]
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        self.inner.extensions_mut()
+    }
+
+    /// Returns the counters for this stream, shared with the write half
+    pub fn stats(&self) -> &Arc<StreamStats> {
+        self.inner.stats()
+    }
+}
+
+impl AsyncRead for KtlsReadHalf {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        // the write half may have sent its close_notify already, don't send
+        // another one
+        if self.close_notify_sent.load(Ordering::Acquire) {
+            self.inner.mark_write_closed();
+        }
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        // only set by the read path, once it replied to an alert
+        if self.inner.is_write_closed() {
+            self.close_notify_sent.store(true, Ordering::Release);
+        }
+        res
+    }
+}
+
+impl AsRawFd for KtlsReadHalf {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsFd for KtlsReadHalf {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+/// The write half of a [KtlsStream], see [KtlsStream::into_split]
+pub struct KtlsWriteHalf {
+    inner: KtlsStream<SharedTcpStream<Arc<TcpStream>>>,
+    close_notify_sent: Arc<AtomicBool>,
+}
+
+impl KtlsWriteHalf {
+    /// Returns the counters for this stream, shared with the read half
+    pub fn stats(&self) -> &Arc<StreamStats> {
+        self.inner.stats()
+    }
+
+    fn sync_close_notify(&mut self) {
+        if self.close_notify_sent.load(Ordering::Acquire) {
+            self.inner.mark_write_closed();
+        }
+    }
+}
+
+impl AsyncWrite for KtlsWriteHalf {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> task::Poll<io::Result<usize>> {
+        self.sync_close_notify();
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> task::Poll<io::Result<usize>> {
+        self.sync_close_notify();
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        self.sync_close_notify();
+        let res = Pin::new(&mut self.inner).poll_shutdown(cx);
+        // a shutdown that's pending or failed can be retried
+        if let task::Poll::Ready(Ok(())) = res {
+            self.close_notify_sent.store(true, Ordering::Release);
+        }
+        res
+    }
+}
+
+impl AsRawFd for KtlsWriteHalf {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsFd for KtlsWriteHalf {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.as_fd()
+    }
+}
+
+impl<S> AsyncRead for SharedTcpStream<S>
+where
+    S: Deref<Target = TcpStream>,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        loop {
+            futures::ready!(self.stream.poll_read_ready(cx))?;
+            match self.stream.try_read(buf.initialize_unfilled()) {
+                Ok(n) => {
+                    buf.advance(n);
+                    return task::Poll::Ready(Ok(()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return task::Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
+
+impl<S> AsyncReadReady for SharedTcpStream<S>
+where
+    S: Deref<Target = TcpStream>,
+{
+    fn poll_read_ready(&self, cx: &mut task::Context<'_>) -> task::Poll<io::Result<()>> {
+        self.stream.poll_read_ready(cx)
+    }
+}
+
+impl<S> AsyncWrite for SharedTcpStream<S>
+where
+    S: Deref<Target = TcpStream>,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> task::Poll<io::Result<usize>> {
+        loop {
+            futures::ready!(self.stream.poll_write_ready(cx))?;
+            match self.stream.try_write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return task::Poll::Ready(res),
+            }
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> task::Poll<io::Result<usize>> {
+        loop {
+            futures::ready!(self.stream.poll_write_ready(cx))?;
+            match self.stream.try_write_vectored(bufs) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return task::Poll::Ready(res),
+            }
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut task::Context<'_>) -> task::Poll<io::Result<()>> {
+        task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut task::Context<'_>,
+    ) -> task::Poll<io::Result<()>> {
+        let sock = socket2::SockRef::from(&*self.stream);
+        task::Poll::Ready(sock.shutdown(std::net::Shutdown::Write))
+    }
+}
+
+impl<S> AsRawFd for SharedTcpStream<S>
+where
+    S: Deref<Target = TcpStream>,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl<S> AsFd for SharedTcpStream<S>
+where
+    S: Deref<Target = TcpStream>,
+{
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.stream.as_fd()
+    }
+}
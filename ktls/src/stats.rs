@@ -0,0 +1,143 @@
+//! Per-connection counters, plus a background task periodically reporting
+//! them, so long-lived tunnels can report bandwidth without instrumenting
+//! every read/write call site.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Counters updated by a [crate::KtlsStream] as data goes through it. Get
+/// them with [crate::KtlsStream::stats].
+#[derive(Debug, Default)]
+pub struct StreamStats {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    control_records: AtomicU64,
+}
+
+/// A point-in-time copy of [StreamStats]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Plaintext bytes returned to the reader
+    pub bytes_read: u64,
+    /// Plaintext bytes accepted from the writer
+    pub bytes_written: u64,
+    /// Number of successful non-empty reads
+    pub reads: u64,
+    /// Number of successful non-empty writes
+    pub writes: u64,
+    /// Non-application records (alerts, session tickets...) received
+    pub control_records: u64,
+}
+
+impl StreamStats {
+    pub(crate) fn record_read(&self, n: usize) {
+        if n > 0 {
+            self.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+            self.reads.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_write(&self, n: usize) {
+        if n > 0 {
+            self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+            self.writes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_control(&self) {
+        self.control_records.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            control_records: self.control_records.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl StatsSnapshot {
+    fn delta(&self, previous: &StatsSnapshot) -> StatsSnapshot {
+        StatsSnapshot {
+            bytes_read: self.bytes_read - previous.bytes_read,
+            bytes_written: self.bytes_written - previous.bytes_written,
+            reads: self.reads - previous.reads,
+            writes: self.writes - previous.writes,
+            control_records: self.control_records - previous.control_records,
+        }
+    }
+}
+
+/// What the callback passed to [spawn_reporter] receives every interval
+#[derive(Debug, Clone, Copy)]
+pub struct StatsReport {
+    /// Counters since the stream was created
+    pub total: StatsSnapshot,
+    /// Counters since the previous report
+    pub delta: StatsSnapshot,
+    /// Time elapsed since the previous report
+    pub elapsed: Duration,
+}
+
+impl StatsReport {
+    /// Read throughput over the last interval, in bytes per second
+    pub fn read_rate(&self) -> f64 {
+        self.delta.bytes_read as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Write throughput over the last interval, in bytes per second
+    pub fn write_rate(&self) -> f64 {
+        self.delta.bytes_written as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Spawn a task calling `report` every `interval` with the stream's counters.
+/// The task exits on its own once the stream (and its halves and clones) is
+/// dropped, after a final report covering the last interval.
+pub fn spawn_reporter(
+    stats: &Arc<StreamStats>,
+    interval: Duration,
+    mut report: impl FnMut(StatsReport) + Send + 'static,
+) -> tokio::task::JoinHandle<()> {
+    let stats = stats.clone();
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // the first tick completes immediately
+        ticker.tick().await;
+
+        let mut previous = StatsSnapshot::default();
+        let mut last = Instant::now();
+        loop {
+            ticker.tick().await;
+
+            // checked first: nothing can be recorded past that point
+            let done = Arc::strong_count(&stats) == 1;
+            let total = stats.snapshot();
+            let now = Instant::now();
+            report(StatsReport {
+                total,
+                delta: total.delta(&previous),
+                elapsed: now - last,
+            });
+            if done {
+                // we were holding the last reference
+                return;
+            }
+
+            previous = total;
+            last = now;
+        }
+    })
+}
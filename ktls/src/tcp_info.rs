@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use crate::ffi::RawTcpInfo;
+
+/// Congestion-level statistics of the TCP connection underneath a
+/// [crate::KtlsStream], from the `TCP_INFO` socket option. Fields the running
+/// kernel doesn't report are zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpInfo {
+    /// Smoothed round-trip time
+    pub rtt: Duration,
+    /// Round-trip time variance
+    pub rtt_var: Duration,
+    /// Lowest round-trip time seen over the connection lifetime
+    pub min_rtt: Duration,
+    /// Total number of retransmitted segments over the connection lifetime
+    pub total_retransmits: u32,
+    /// Segments currently considered lost
+    pub lost: u32,
+    /// Congestion window, in segments
+    pub snd_cwnd: u32,
+    /// Sender maximum segment size
+    pub snd_mss: u32,
+    /// Current pacing rate, in bytes per second
+    pub pacing_rate: u64,
+    /// Most recent delivery rate estimate, in bytes per second
+    pub delivery_rate: u64,
+    /// Bytes acknowledged by the peer
+    pub bytes_acked: u64,
+    /// Bytes received from the peer
+    pub bytes_received: u64,
+    /// Bytes written but not sent yet (not the ones in flight)
+    pub notsent_bytes: u32,
+}
+
+impl From<RawTcpInfo> for TcpInfo {
+    fn from(raw: RawTcpInfo) -> Self {
+        Self {
+            rtt: Duration::from_micros(raw.rtt.into()),
+            rtt_var: Duration::from_micros(raw.rttvar.into()),
+            // ~0 until there's a sample
+            min_rtt: match raw.min_rtt {
+                u32::MAX => Duration::ZERO,
+                min_rtt => Duration::from_micros(min_rtt.into()),
+            },
+            total_retransmits: raw.total_retrans,
+            lost: raw.lost,
+            snd_cwnd: raw.snd_cwnd,
+            snd_mss: raw.snd_mss,
+            pacing_rate: raw.pacing_rate,
+            delivery_rate: raw.delivery_rate,
+            bytes_acked: raw.bytes_acked,
+            bytes_received: raw.bytes_received,
+            notsent_bytes: raw.notsent_bytes,
+        }
+    }
+}
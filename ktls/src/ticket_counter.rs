@@ -0,0 +1,108 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use rustls::{
+    client::{ClientSessionStore, Tls12ClientSessionValue, Tls13ClientSessionValue},
+    NamedGroup, ServerName,
+};
+
+/// A [ClientSessionStore] wrapper that keeps track of how many TLS 1.3
+/// tickets are stored for each server, so clients can wait for them before
+/// offloading (see [crate::KtlsConfig::with_session_tickets]): tickets that
+/// arrive afterwards can't be used for resumption.
+///
+/// Counts are approximate if the wrapped store evicts tickets on its own.
+pub struct TicketCounter {
+    inner: Arc<dyn ClientSessionStore>,
+    tls13_tickets: Mutex<HashMap<ServerName, TicketCounts>>,
+}
+
+#[derive(Default)]
+struct TicketCounts {
+    stored: usize,
+    received: usize,
+}
+
+impl TicketCounter {
+    pub fn new(inner: Arc<dyn ClientSessionStore>) -> Arc<Self> {
+        Arc::new(Self {
+            inner,
+            tls13_tickets: Default::default(),
+        })
+    }
+
+    /// How many TLS 1.3 tickets are stored for `server_name`
+    pub fn tls13_tickets(&self, server_name: &ServerName) -> usize {
+        self.tickets().get(server_name).map_or(0, |c| c.stored)
+    }
+
+    /// How many TLS 1.3 tickets were ever stored for `server_name`, taken
+    /// ones included: the difference between two calls is how many came in
+    /// meanwhile, whatever earlier connections left in the store
+    pub fn tls13_tickets_received(&self, server_name: &ServerName) -> usize {
+        self.tickets().get(server_name).map_or(0, |c| c.received)
+    }
+
+    fn tickets(&self) -> std::sync::MutexGuard<'_, HashMap<ServerName, TicketCounts>> {
+        self.tls13_tickets
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl std::fmt::Debug for TicketCounter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TicketCounter").finish_non_exhaustive()
+    }
+}
+
+impl ClientSessionStore for TicketCounter {
+    fn set_kx_hint(&self, server_name: &ServerName, group: NamedGroup) {
+        self.inner.set_kx_hint(server_name, group)
+    }
+
+    fn kx_hint(&self, server_name: &ServerName) -> Option<NamedGroup> {
+        self.inner.kx_hint(server_name)
+    }
+
+    fn set_tls12_session(&self, server_name: &ServerName, value: Tls12ClientSessionValue) {
+        self.inner.set_tls12_session(server_name, value)
+    }
+
+    fn tls12_session(&self, server_name: &ServerName) -> Option<Tls12ClientSessionValue> {
+        self.inner.tls12_session(server_name)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName) {
+        self.inner.remove_tls12_session(server_name)
+    }
+
+    fn insert_tls13_ticket(&self, server_name: &ServerName, value: Tls13ClientSessionValue) {
+        self.inner.insert_tls13_ticket(server_name, value);
+        let mut tickets = self.tickets();
+        let counts = tickets.entry(server_name.clone()).or_default();
+        counts.stored += 1;
+        counts.received += 1;
+    }
+
+    fn take_tls13_ticket(&self, server_name: &ServerName) -> Option<Tls13ClientSessionValue> {
+        let ticket = self.inner.take_tls13_ticket(server_name);
+        let mut tickets = self.tickets();
+        match ticket {
+            Some(_) => {
+                if let Some(counts) = tickets.get_mut(server_name) {
+                    counts.stored = counts.stored.saturating_sub(1);
+                }
+            }
+            // the wrapped store evicted them
+            None => {
+                if let Some(counts) = tickets.get_mut(server_name) {
+                    counts.stored = 0;
+                }
+            }
+        }
+        ticket
+    }
+}
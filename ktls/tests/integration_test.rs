@@ -1,6 +1,6 @@
 use std::{
     io,
-    os::fd::{AsRawFd, RawFd},
+    os::fd::{AsFd, AsRawFd, BorrowedFd},
     sync::Arc,
     task,
     time::Duration,
@@ -45,6 +45,13 @@ async fn compatible_ciphers() {
     }
 }
 
+#[tokio::test]
+async fn compatible_versions() {
+    let cc = ktls::CompatibleCiphers::new().await.unwrap();
+    assert!(cc.is_version_compatible(rustls::ProtocolVersion::TLSv1_2));
+    assert!(cc.is_version_compatible(rustls::ProtocolVersion::TLSv1_3));
+}
+
 #[tokio::test(flavor = "current_thread")]
 async fn compatible_ciphers_single_thread() {
     let cc = ktls::CompatibleCiphers::new().await.unwrap();
@@ -60,6 +67,22 @@ async fn compatible_ciphers_single_thread() {
     }
 }
 
+#[test]
+fn compatible_ciphers_sync() {
+    let cc = ktls::CompatibleCiphers::new_sync().unwrap();
+    for suite in [
+        rustls::cipher_suite::TLS13_AES_128_GCM_SHA256,
+        rustls::cipher_suite::TLS13_AES_256_GCM_SHA384,
+        rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+        rustls::cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+        rustls::cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+        rustls::cipher_suite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    ] {
+        assert!(cc.is_compatible(&suite));
+    }
+    assert!(cc.is_version_compatible(rustls::ProtocolVersion::TLSv1_3));
+}
+
 #[tokio::test]
 async fn ktls_server_rustls_client_tls_1_3_aes_128_gcm() {
     server_test(&TLS13, TLS13_AES_128_GCM_SHA256).await;
@@ -465,6 +488,1098 @@ async fn client_test_inner(
     jh.await.unwrap();
 }
 
+/// Both sides keep the negotiated cipher suite and protocol version
+#[tokio::test]
+async fn ktls_connection_info_negotiated() {
+    for version in [&TLS13, &TLS12] {
+        let (server_config, client_config) = test_configs_with_versions(&[version]);
+        let (server, client) = offloaded_pair(server_config, client_config).await;
+
+        for info in [server.connection_info(), client.connection_info()] {
+            assert_eq!(info.protocol_version(), Some(version.version));
+            let suite_version = info.cipher_suite().map(|suite| suite.version().version);
+            assert_eq!(suite_version, Some(version.version));
+        }
+        assert_eq!(
+            server.connection_info().cipher_suite(),
+            client.connection_info().cipher_suite()
+        );
+    }
+}
+
+/// The server sends its TLS 1.3 session tickets then a burst of small
+/// records right after the handshake, all of which rustls has buffered by
+/// the time the client offloads: every byte must come out, in order, even
+/// when read a few bytes at a time.
+#[tokio::test]
+async fn ktls_client_drain_interleaved_tickets_and_data() {
+    const CHUNKS: usize = 64;
+
+    let (mut server_config, client_config) = test_configs_with_versions(&[&TLS13]);
+    server_config.send_tls13_tickets = 4;
+
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    let expected: Vec<u8> = SERVER_PAYLOAD[..CHUNKS * 100].to_vec();
+    let jh = tokio::spawn(async move {
+        let (stream, _) = ln.accept().await.unwrap();
+        let mut stream = acceptor.accept(stream).await.unwrap();
+        for chunk in SERVER_PAYLOAD[..CHUNKS * 100].chunks(100) {
+            stream.write_all(chunk).await.unwrap();
+            stream.flush().await.unwrap();
+        }
+        stream.shutdown().await.unwrap();
+    });
+
+    let tls_connector = TlsConnector::from(Arc::new(client_config));
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let stream = tls_connector
+        .connect("localhost".try_into().unwrap(), CorkStream::new(stream))
+        .await
+        .unwrap();
+
+    // let the tickets and the data pile up before offloading
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut stream = ktls::config_ktls_client(stream).await.unwrap();
+
+    let mut received = vec![];
+    let mut buf = [0u8; 7];
+    while received.len() < expected.len() {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_ne!(n, 0, "EOF after {} bytes", received.len());
+        received.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(received, expected);
+
+    jh.await.unwrap();
+}
+
+/// Health checkers complete the handshake and send a close_notify right
+/// away, possibly before the server offloads: the first read must be a clean
+/// EOF, even though the TCP connection is still open.
+#[tokio::test]
+async fn ktls_server_close_notify_during_drain() {
+    let (server_config, client_config) = test_configs();
+
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    let jh = tokio::spawn(async move {
+        let (stream, _) = ln.accept().await.unwrap();
+        let stream = acceptor.accept(CorkStream::new(stream)).await.unwrap();
+
+        // let the close_notify arrive before offloading
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut stream = ktls::config_ktls_server(stream).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("read should see the close_notify")
+            .unwrap();
+        assert_eq!(n, 0);
+    });
+
+    let tls_connector = TlsConnector::from(Arc::new(client_config));
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut stream = tls_connector
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+
+    // close_notify, but no FIN
+    stream.get_mut().1.send_close_notify();
+    stream.flush().await.unwrap();
+
+    jh.await.unwrap();
+}
+
+#[tokio::test]
+async fn ktls_server_fallback_to_rustls() {
+    let (server_config, client_config) = test_configs();
+
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    let jh = tokio::spawn(async move {
+        let (stream, _) = ln.accept().await.unwrap();
+        let stream = acceptor.accept(CorkStream::new(stream)).await.unwrap();
+
+        // pretend the kernel supports no cipher at all
+        let config = ktls::KtlsConfig::new().with_compatible_ciphers(Arc::new(Default::default()));
+        let mut stream = ktls::config_ktls_server_or_fallback(stream, &config)
+            .await
+            .unwrap();
+        assert!(!stream.is_offloaded());
+
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        stream.write_all(b"world").await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let tls_connector = TlsConnector::from(Arc::new(client_config));
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut stream = tls_connector
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = vec![];
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"world");
+
+    jh.await.unwrap();
+}
+
+#[tokio::test]
+async fn ktls_acceptor_config_selector() {
+    let (server_config, client_config) = test_configs();
+    let server_config = Arc::new(server_config);
+
+    let acceptor = ktls::KtlsAcceptor::with_config_selector(move |hello| {
+        (hello.server_name() == Some("localhost")).then(|| server_config.clone())
+    });
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    let jh = tokio::spawn(async move {
+        let (stream, _) = ln.accept().await.unwrap();
+        let ktls::AcceptedStream::Ktls(mut stream) = acceptor.accept(stream).await.unwrap() else {
+            panic!("connection should be offloaded");
+        };
+
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        stream.write_all(b"world").await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let tls_connector = TlsConnector::from(Arc::new(client_config));
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut stream = tls_connector
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = vec![];
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"world");
+
+    jh.await.unwrap();
+}
+
+/// The kernel hands every record that's ready to the same read: record mode
+/// only tells whether a read ended on a record boundary
+#[tokio::test]
+async fn ktls_record_reads_with_queued_records() {
+    let (server_config, client_config) = test_configs();
+    let (server, mut client) = offloaded_server(server_config, client_config).await;
+    let mut server = server.with_record_reads(true);
+
+    // two records, both queued by the time the server reads
+    client.write_all(b"hello").await.unwrap();
+    client.flush().await.unwrap();
+    client.write_all(b"world").await.unwrap();
+    client.flush().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut buf = [0u8; 7];
+    let n = server.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"hellowo");
+    assert!(!server.at_record_boundary());
+
+    let n = server.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"rld");
+    assert!(server.at_record_boundary());
+}
+
+/// Control records reach the handler whole, however small the read that
+/// ran into them
+#[tokio::test]
+async fn ktls_control_record_not_truncated() {
+    let (server_config, client_config) = test_configs();
+    let (server, client) = offloaded_pair(server_config, client_config).await;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut server = server.with_control_record_handler(move |record| {
+        tx.send(record).unwrap();
+    });
+
+    // an (unknown) handshake message bigger than any read below
+    let mut message = vec![0xfe, 0x00, 0x13, 0x88];
+    message.resize(4 + 5000, 0xab);
+    send_record(&client, 22, &message);
+    send_record(&client, 23, b"hello");
+
+    let mut buf = [0u8; 8];
+    let n = server.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"hello");
+    assert_eq!(
+        rx.try_recv().unwrap(),
+        ktls::ControlRecord::Handshake(message)
+    );
+}
+
+/// A change_cipher_spec record after the handshake fails the read
+#[tokio::test]
+async fn ktls_change_cipher_spec_fails_read() {
+    let (server_config, client_config) = test_configs();
+    let (mut server, client) = offloaded_pair(server_config, client_config).await;
+
+    send_record(&client, 20, &[1]);
+
+    let mut buf = [0u8; 8];
+    let err = server.read(&mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+/// Peeking handles control records like reads do, and leaves the data
+#[tokio::test]
+async fn ktls_peek_through_control_record() {
+    let (server_config, client_config) = test_configs();
+    let (server, client) = offloaded_pair(server_config, client_config).await;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut server = server.with_control_record_handler(move |record| {
+        tx.send(record).unwrap();
+    });
+
+    send_record(&client, 22, &[0xfe, 0, 0, 1, 0xab]);
+    send_record(&client, 23, b"hello");
+
+    let mut buf = [0u8; 8];
+    let n = server.peek(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"hello");
+    assert_eq!(
+        rx.try_recv().unwrap(),
+        ktls::ControlRecord::Handshake(vec![0xfe, 0, 0, 1, 0xab])
+    );
+
+    let n = server.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"hello");
+}
+
+/// What coalesced writes buffered goes out before a sendfile, and before the
+/// socket is handed over
+#[tokio::test]
+async fn ktls_buffered_writes_before_sendfile_and_handoff() {
+    let (server_config, client_config) = test_configs();
+    let (server, mut client) = offloaded_pair(server_config, client_config).await;
+    let mut server = server.with_coalesced_writes(true);
+
+    let path = std::env::temp_dir().join(format!("ktls-sendfile-{}", std::process::id()));
+    std::fs::write(&path, b"world").unwrap();
+    let file = std::fs::File::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    server.write_all(b"hello ").await.unwrap();
+    assert_eq!(server.sendfile(&file, 0, 5, |_| {}).await.unwrap(), 5);
+    server.write_all(b"!").await.unwrap();
+    // the file's bytes count as written too
+    assert_eq!(server.stats().snapshot().bytes_written, 11);
+    let (_sock, _) = server.into_std().unwrap();
+
+    let mut buf = [0u8; 12];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello world!");
+}
+
+/// The halves of a split stream read and write independently, and the read
+/// half's reply to a close_notify closes the write half
+#[tokio::test]
+async fn ktls_into_split_read_then_write() {
+    let (server_config, client_config) = test_configs();
+    let (server, mut client) = offloaded_pair(server_config, client_config).await;
+    let (mut read_half, mut write_half) = server.into_split();
+
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    read_half.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    write_half.write_all(b"pong").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+
+    client.shutdown().await.unwrap();
+    assert_eq!(read_half.read(&mut buf).await.unwrap(), 0);
+    // the read half replied with a close_notify of its own
+    assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    assert_eq!(write_half.write(b"late").await.unwrap(), 0);
+}
+
+/// An atomic vectored write is accepted whole even past the record size cap,
+/// the rest goes out on flush
+#[tokio::test]
+async fn ktls_atomic_vectored_write_past_record_cap() {
+    let (server_config, client_config) = test_configs();
+    let (server, mut client) = offloaded_pair(server_config, client_config).await;
+    let mut server = server
+        .with_atomic_writes(true)
+        .with_max_record_size(Some(4));
+
+    let bufs = [io::IoSlice::new(b"hello"), io::IoSlice::new(b" world")];
+    assert_eq!(server.write_vectored(&bufs).await.unwrap(), 11);
+    server.flush().await.unwrap();
+
+    let mut buf = [0u8; 11];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello world");
+}
+
+/// Coalesced writes still buffered when the read side replies to a
+/// close_notify are dropped, not sent after it
+#[tokio::test]
+async fn ktls_buffered_writes_after_close_notify_reply() {
+    let (server_config, client_config) = test_configs();
+    let (server, mut client) = offloaded_pair(server_config, client_config).await;
+    let mut server = server.with_coalesced_writes(true);
+
+    server.write_all(b"hello").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut buf = [0u8; 5];
+    assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+
+    let err = server.flush().await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+}
+
+/// Both sides tell a full handshake from a resumed one, and how it resumed
+#[tokio::test]
+async fn ktls_handshake_kind_resumption() {
+    use ktls::HandshakeKind::*;
+
+    // TLS 1.3, from a ticket the client waited for
+    let (server_config, mut client_config) = test_configs_with_versions(&[&TLS13]);
+    let counter =
+        ktls::TicketCounter::new(Arc::new(rustls::client::ClientSessionMemoryCache::new(32)));
+    client_config.resumption = Resumption::store(counter.clone());
+    let (server_config, client_config) = (Arc::new(server_config), Arc::new(client_config));
+    let first = handshake_kinds(&server_config, &client_config, &counter, 2).await;
+    let second = handshake_kinds(&server_config, &client_config, &counter, 0).await;
+    assert_eq!([first, second], [(Full, Full), (ResumedPsk, ResumedPsk)]);
+
+    // TLS 1.2, from a ticket
+    let (mut server_config, mut client_config) = test_configs_with_versions(&[&TLS12]);
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
+    client_config.resumption = Resumption::store(counter.clone());
+    let (server_config, client_config) = (Arc::new(server_config), Arc::new(client_config));
+    let first = handshake_kinds(&server_config, &client_config, &counter, 0).await;
+    let second = handshake_kinds(&server_config, &client_config, &counter, 0).await;
+    assert_eq!(
+        [first, second],
+        [(Full, Full), (ResumedTicket, ResumedTicket)]
+    );
+
+    // TLS 1.2, from a session ID the server kept
+    let (server_config, mut client_config) = test_configs_with_versions(&[&TLS12]);
+    let counter =
+        ktls::TicketCounter::new(Arc::new(rustls::client::ClientSessionMemoryCache::new(32)));
+    client_config.resumption = Resumption::store(counter.clone());
+    let (server_config, client_config) = (Arc::new(server_config), Arc::new(client_config));
+    let first = handshake_kinds(&server_config, &client_config, &counter, 0).await;
+    let second = handshake_kinds(&server_config, &client_config, &counter, 0).await;
+    assert_eq!(
+        [first, second],
+        [(Full, Full), (ResumedSessionId, ResumedSessionId)]
+    );
+}
+
+/// Once the peer rekeys, every read fails, peeks included
+#[tokio::test]
+async fn ktls_key_update_fails_every_read() {
+    let (server_config, client_config) = test_configs();
+    let (mut server, client) = offloaded_pair(server_config, client_config).await;
+
+    // KeyUpdate, update_not_requested
+    send_record(&client, 22, &[24, 0, 0, 1, 0]);
+
+    let mut buf = [0u8; 8];
+    for _ in 0..2 {
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+    let err = server.peek(&mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+}
+
+/// A KeyUpdate is caught even behind another message in the same record
+#[tokio::test]
+async fn ktls_key_update_after_ticket_in_one_record() {
+    let (server_config, client_config) = test_configs();
+    let (mut server, client) = offloaded_pair(server_config, client_config).await;
+
+    // NewSessionTicket: lifetime, age_add, nonce, ticket, no extensions
+    let mut record = vec![
+        4, 0, 0, 18, 0, 0, 0, 60, 0, 0, 0, 1, 1, 0, 0, 4, 1, 2, 3, 4, 0, 0,
+    ];
+    // KeyUpdate, update_not_requested
+    record.extend_from_slice(&[24, 0, 0, 1, 0]);
+    send_record(&client, 22, &record);
+
+    let mut buf = [0u8; 8];
+    let err = server.read(&mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+}
+
+/// Once the peer sent a fatal alert, every read fails with it
+#[tokio::test]
+async fn ktls_fatal_alert_fails_every_read() {
+    let (server_config, client_config) = test_configs();
+    let (mut server, client) = offloaded_pair(server_config, client_config).await;
+
+    // fatal, handshake_failure
+    send_record(&client, 21, &[2, 40]);
+
+    let mut buf = [0u8; 8];
+    for _ in 0..2 {
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert!(matches!(
+            err.get_ref().and_then(|e| e.downcast_ref::<ktls::Error>()),
+            Some(ktls::Error::Alert { .. })
+        ));
+    }
+    let err = server.peek(&mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+}
+
+/// Plaintext rustls decrypted during the handshake is picked up even when
+/// the drain is skipped, and counts towards the drain limit
+#[tokio::test]
+async fn ktls_server_drain_limit_skip_drain() {
+    let (server_config, client_config) = test_configs();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    let jh = tokio::spawn(async move {
+        let (stream, _) = ln.accept().await.unwrap();
+        let stream = acceptor.accept(CorkStream::new(stream)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let config = ktls::KtlsConfig::new()
+            .with_skip_drain(true)
+            .with_drain_limit(4);
+        let Err(err) = ktls::config_ktls_server_with_config(stream, &config).await else {
+            panic!("the drain limit should be exceeded");
+        };
+        assert!(matches!(err, ktls::Error::DrainLimitExceeded(4)), "{err}");
+    });
+
+    // the data is sent along with the client's Finished, so the server
+    // decrypts it as part of the handshake
+    let _tcp = tokio::task::spawn_blocking(move || {
+        let mut conn =
+            rustls::ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap())
+                .unwrap();
+        let mut tcp = std::net::TcpStream::connect(addr).unwrap();
+        while conn.is_handshaking() {
+            while conn.wants_write() {
+                conn.write_tls(&mut tcp).unwrap();
+            }
+            conn.read_tls(&mut tcp).unwrap();
+            conn.process_new_packets().unwrap();
+        }
+        std::io::Write::write_all(&mut conn.writer(), b"too long").unwrap();
+        conn.send_close_notify();
+        while conn.wants_write() {
+            conn.write_tls(&mut tcp).unwrap();
+        }
+        tcp
+    })
+    .await
+    .unwrap();
+
+    jh.await.unwrap();
+}
+
+/// A rustls config without secret extraction is caught before the stream is
+/// consumed: the connection falls back, or fails with the right error
+#[tokio::test]
+async fn ktls_secret_extraction_disabled() {
+    let (mut server_config, mut client_config) = test_configs();
+    server_config.enable_secret_extraction = false;
+    client_config.enable_secret_extraction = false;
+
+    let acceptor = ktls::KtlsAcceptor::new(Arc::new(server_config));
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    let jh = tokio::spawn(async move {
+        let (stream, _) = ln.accept().await.unwrap();
+        let Err(err) = acceptor.accept(stream).await else {
+            panic!("the offload should fail");
+        };
+        assert!(
+            matches!(err, ktls::Error::SecretExtractionDisabled),
+            "{err}"
+        );
+    });
+
+    let connector = ktls::KtlsConnector::new(Arc::new(client_config))
+        .with_config(ktls::KtlsConfig::new().with_fallback(true));
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let stream = connector
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+    assert!(!stream.is_offloaded());
+
+    jh.await.unwrap();
+}
+
+#[tokio::test]
+async fn ktls_acceptor_rekey_policy_fallback() {
+    let (server_config, client_config) = test_configs();
+    let config = ktls::KtlsConfig::new()
+        .with_rekey_policy(ktls::RekeyPolicy::Userspace)
+        .with_fallback(true);
+    let acceptor = ktls::KtlsAcceptor::new(Arc::new(server_config)).with_config(config);
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    let jh = tokio::spawn(async move {
+        let (stream, _) = ln.accept().await.unwrap();
+        // TLS 1.3 is negotiated, which the policy keeps in userspace
+        let ktls::AcceptedStream::Rustls(mut stream) = acceptor.accept(stream).await.unwrap()
+        else {
+            panic!("connection should stay on rustls");
+        };
+
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        stream.write_all(b"world").await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let tls_connector = TlsConnector::from(Arc::new(client_config));
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut stream = tls_connector
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = vec![];
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"world");
+
+    jh.await.unwrap();
+}
+
+/// Past the concurrency limit and the queue, `accept` fails right away
+#[tokio::test]
+async fn ktls_acceptor_handshake_queue_full() {
+    let (server_config, _) = test_configs();
+    let acceptor = ktls::KtlsAcceptor::new(Arc::new(server_config))
+        .with_max_concurrent_handshakes(1)
+        .with_max_queued_handshakes(1);
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    // clients that never send a ClientHello: the first one takes the only
+    // permit, the second one waits
+    let _clients = [
+        TcpStream::connect(addr).await.unwrap(),
+        TcpStream::connect(addr).await.unwrap(),
+        TcpStream::connect(addr).await.unwrap(),
+    ];
+    for _ in 0..2 {
+        let (stream, _) = ln.accept().await.unwrap();
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move { acceptor.accept(stream).await });
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let (stream, _) = ln.accept().await.unwrap();
+    let res = tokio::time::timeout(Duration::from_secs(1), acceptor.accept(stream))
+        .await
+        .expect("accept should fail right away");
+    assert!(matches!(res, Err(ktls::Error::TooManyHandshakes)));
+}
+
+#[test]
+#[should_panic]
+fn ktls_acceptor_no_handshakes_allowed() {
+    let (server_config, _) = test_configs();
+    let _ = ktls::KtlsAcceptor::new(Arc::new(server_config)).with_max_concurrent_handshakes(0);
+}
+
+/// The connector reaches the server through a SOCKS5 proxy, authenticating
+/// with a username and a password
+#[tokio::test]
+async fn ktls_connector_socks5_proxy() {
+    let (server_config, client_config) = test_configs();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let ln = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = ln.local_addr().unwrap();
+
+    let jh = tokio::spawn(async move {
+        let (stream, _) = ln.accept().await.unwrap();
+        let mut stream = acceptor.accept(stream).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let proxy = socks5_stub(target, [1, 0]).await;
+    let connector = ktls::KtlsConnector::new(Arc::new(client_config))
+        .with_config(ktls::KtlsConfig::new().with_offload(false))
+        .with_socks5_proxy(ktls::socks5::Proxy::new(proxy.to_string()).with_credentials("u", "p"));
+    let ktls::MaybeKtlsStream::Rustls(mut stream) = connector
+        .connect_to("localhost".try_into().unwrap(), "localhost", target.port())
+        .await
+        .unwrap()
+    else {
+        panic!("offload is disabled");
+    };
+
+    let mut buf = vec![];
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"hello");
+    jh.await.unwrap();
+}
+
+/// A reply to the credentials that isn't RFC 1929's is rejected
+#[tokio::test]
+async fn socks5_auth_reply_version() {
+    let proxy = socks5_stub("127.0.0.1:1".parse().unwrap(), [5, 0]).await;
+    let err = ktls::socks5::connect(proxy, "localhost", 443, Some(("u", "p")))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn ktls_offload_disabled_by_config() {
+    let (server_config, client_config) = test_configs();
+    let config = ktls::KtlsConfig::new().with_offload(false);
+
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    let server_config = config.clone();
+    let jh = tokio::spawn(async move {
+        let (stream, _) = ln.accept().await.unwrap();
+        let stream = acceptor.accept(CorkStream::new(stream)).await.unwrap();
+
+        let Err(err) = ktls::config_ktls_server_or_return(stream, &server_config).await else {
+            panic!("offload should be disabled");
+        };
+        assert!(matches!(err.error, ktls::Error::OffloadDisabled));
+        let stream = err.stream.expect("stream given back");
+        let mut stream = ktls::MaybeKtlsStream::Rustls(Box::new(stream.into()));
+
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        stream.write_all(b"world").await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let connector = ktls::KtlsConnector::new(Arc::new(client_config)).with_config(config);
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut stream = connector
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+    assert!(!stream.is_offloaded());
+
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = vec![];
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"world");
+
+    jh.await.unwrap();
+}
+
+/// Tickets left in the store by an earlier connection don't count: the second
+/// connection waits for its own
+#[tokio::test]
+async fn ktls_session_tickets_reused_store() {
+    let (server_config, mut client_config) = test_configs();
+    let counter =
+        ktls::TicketCounter::new(Arc::new(rustls::client::ClientSessionMemoryCache::new(32)));
+    client_config.resumption = Resumption::store(counter.clone());
+
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+    let jh = tokio::spawn(async move {
+        let mut streams = vec![];
+        for _ in 0..2 {
+            let (stream, _) = ln.accept().await.unwrap();
+            streams.push(acceptor.accept(stream).await.unwrap());
+        }
+        // keep both connections open until the client is done
+        for mut stream in streams {
+            let _ = stream.read_to_end(&mut vec![]).await;
+        }
+    });
+
+    let server_name: rustls::ServerName = "localhost".try_into().unwrap();
+    let tls_connector = TlsConnector::from(Arc::new(client_config));
+    let config =
+        ktls::KtlsConfig::new().with_session_tickets(counter.clone(), 2, Duration::from_secs(5));
+    for _ in 0..2 {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let stream = tls_connector
+            .connect(server_name.clone(), CorkStream::new(stream))
+            .await
+            .unwrap();
+
+        let before = counter.tls13_tickets_received(&server_name);
+        let res = ktls::config_ktls_client_with_config(stream, &config).await;
+        assert!(res.is_ok(), "{:?}", res.err());
+        assert!(counter.tls13_tickets_received(&server_name) >= before + 2);
+    }
+
+    jh.await.unwrap();
+}
+
+/// Application data read while waiting for tickets counts towards the drain
+/// limit
+#[tokio::test]
+async fn ktls_session_tickets_drain_limit() {
+    let (server_config, mut client_config) = test_configs();
+    let counter =
+        ktls::TicketCounter::new(Arc::new(rustls::client::ClientSessionMemoryCache::new(32)));
+    client_config.resumption = Resumption::store(counter.clone());
+
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+    let jh = tokio::spawn(async move {
+        let (stream, _) = ln.accept().await.unwrap();
+        let mut stream = acceptor.accept(stream).await.unwrap();
+        stream.write_all(b"too much").await.unwrap();
+        let _ = stream.read_to_end(&mut vec![]).await;
+    });
+
+    let stream = TlsConnector::from(Arc::new(client_config))
+        .connect(
+            "localhost".try_into().unwrap(),
+            CorkStream::new(TcpStream::connect(addr).await.unwrap()),
+        )
+        .await
+        .unwrap();
+    // more tickets than the server sends, so the wait only ends on the limit
+    let config = ktls::KtlsConfig::new()
+        .with_session_tickets(counter, 100, Duration::from_secs(5))
+        .with_drain_limit(4);
+    let Err(err) = ktls::config_ktls_client_with_config(stream, &config).await else {
+        panic!("the drain limit should be exceeded");
+    };
+    assert!(matches!(err, ktls::Error::DrainLimitExceeded(4)));
+
+    jh.await.unwrap();
+}
+
+/// The reporter reports the last interval once the stream is dropped, then
+/// exits
+#[tokio::test]
+async fn stats_reporter_final_report() {
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+    let client = TcpStream::connect(addr).await.unwrap();
+    let (mut peer, _) = ln.accept().await.unwrap();
+
+    let mut stream = ktls::KtlsStream::new(client, None);
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let reporter =
+        ktls::stats::spawn_reporter(stream.stats(), Duration::from_millis(50), move |report| {
+            tx.send(report).unwrap();
+        });
+
+    stream.write_all(b"hello").await.unwrap();
+    drop(stream);
+    tokio::time::timeout(Duration::from_secs(5), reporter)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut reports = vec![];
+    while let Ok(report) = rx.try_recv() {
+        reports.push(report);
+    }
+    let last = reports.last().expect("a final report");
+    assert_eq!(last.total.bytes_written, 5);
+    let delta: u64 = reports
+        .iter()
+        .map(|report| report.delta.bytes_written)
+        .sum();
+    assert_eq!(delta, 5);
+
+    let mut buf = [0u8; 5];
+    peer.read_exact(&mut buf).await.unwrap();
+}
+
+/// A socket that never takes the close_notify is closed after the timeout
+#[tokio::test]
+async fn close_notify_on_drop_timeout() {
+    let dropped = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let io = StalledWriter {
+        sock: std::net::UdpSocket::bind("127.0.0.1:0").unwrap(),
+        dropped: dropped.clone(),
+    };
+    let mut stream = ktls::KtlsStream::new(io, None)
+        .with_coalesced_writes(true)
+        .close_notify_on_drop()
+        .with_timeout(Duration::from_millis(200));
+    // buffered, it has to go out before the close_notify
+    stream.write_all(b"hello").await.unwrap();
+    drop(stream);
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!dropped.load(std::sync::atomic::Ordering::SeqCst));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(dropped.load(std::sync::atomic::Ordering::SeqCst));
+}
+
+#[test]
+fn hw_offload_loopback() {
+    let loopback = std::net::IpAddr::from([127, 0, 0, 1]);
+
+    // the loopback device has no NIC to offload to
+    let hw = ktls::HwOffload::detect(loopback).unwrap();
+    assert_eq!(
+        hw,
+        ktls::HwOffload {
+            interface: "lo".into(),
+            tx: false,
+            rx: false
+        }
+    );
+    assert_eq!(ktls::HwOffload::detect_on("lo").unwrap(), hw);
+    assert!(ktls::HwOffload::detect_on("no-such-if0").is_err());
+
+    let support = ktls::KernelSupport::detect_for(loopback);
+    assert_eq!(support.hw_offload, Some(hw));
+    assert!(ktls::KernelSupport::detect().hw_offload.is_none());
+}
+
+/// Configs for both ends of a loopback connection, with a self-signed
+/// `localhost` certificate and secret extraction enabled
+fn test_configs() -> (ServerConfig, ClientConfig) {
+    test_configs_with_versions(&[&TLS13, &TLS12])
+}
+
+/// [test_configs], limited to `versions`
+fn test_configs_with_versions(
+    versions: &[&'static SupportedProtocolVersion],
+) -> (ServerConfig, ClientConfig) {
+    let cert = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+    let mut server_config = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(cert.serialize_der().unwrap())],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )
+        .unwrap();
+    server_config.enable_secret_extraction = true;
+
+    let mut root_certs = RootCertStore::empty();
+    root_certs
+        .add(&rustls::Certificate(cert.serialize_der().unwrap()))
+        .unwrap();
+    let mut client_config = ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .unwrap()
+        .with_root_certificates(root_certs)
+        .with_no_client_auth();
+    client_config.enable_secret_extraction = true;
+
+    (server_config, client_config)
+}
+
+/// How the server and the client saw the session being established, over a
+/// fresh connection. `tickets` TLS 1.3 tickets are waited for before the
+/// client offloads, so the next connection can resume.
+async fn handshake_kinds(
+    server_config: &Arc<ServerConfig>,
+    client_config: &Arc<ClientConfig>,
+    counter: &Arc<ktls::TicketCounter>,
+    tickets: usize,
+) -> (ktls::HandshakeKind, ktls::HandshakeKind) {
+    let acceptor = tokio_rustls::TlsAcceptor::from(server_config.clone());
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = ln.accept().await.unwrap();
+        let stream = acceptor.accept(CorkStream::new(stream)).await.unwrap();
+        let mut stream = ktls::config_ktls_server(stream).await.unwrap();
+        let kind = stream.connection_info().handshake_kind();
+        let _ = stream.read_to_end(&mut vec![]).await;
+        kind
+    });
+
+    let server_name: rustls::ServerName = "localhost".try_into().unwrap();
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let stream = TlsConnector::from(client_config.clone())
+        .connect(server_name, CorkStream::new(stream))
+        .await
+        .unwrap();
+    let config = ktls::KtlsConfig::new().with_session_tickets(
+        counter.clone(),
+        tickets,
+        Duration::from_secs(5),
+    );
+    let mut client = ktls::config_ktls_client_with_config(stream, &config)
+        .await
+        .unwrap();
+    let client_kind = client.connection_info().handshake_kind();
+    client.shutdown().await.unwrap();
+
+    (server.await.unwrap(), client_kind)
+}
+
+/// An offloaded server stream, and the rustls client connected to it
+async fn offloaded_server(
+    server_config: ServerConfig,
+    client_config: ClientConfig,
+) -> (
+    ktls::KtlsStream<TcpStream>,
+    tokio_rustls::client::TlsStream<TcpStream>,
+) {
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = ln.accept().await.unwrap();
+        let stream = acceptor.accept(CorkStream::new(stream)).await.unwrap();
+        ktls::config_ktls_server(stream).await.unwrap()
+    });
+
+    let tls_connector = TlsConnector::from(Arc::new(client_config));
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let client = tls_connector
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+
+    (server.await.unwrap(), client)
+}
+
+/// Both ends of a loopback connection, offloaded
+async fn offloaded_pair(
+    server_config: ServerConfig,
+    client_config: ClientConfig,
+) -> (ktls::KtlsStream<TcpStream>, ktls::KtlsStream<TcpStream>) {
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let ln = TcpListener::bind("[::]:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = ln.accept().await.unwrap();
+        let stream = acceptor.accept(CorkStream::new(stream)).await.unwrap();
+        ktls::config_ktls_server(stream).await.unwrap()
+    });
+
+    let tls_connector = TlsConnector::from(Arc::new(client_config));
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let stream = tls_connector
+        .connect("localhost".try_into().unwrap(), CorkStream::new(stream))
+        .await
+        .unwrap();
+    let client = ktls::config_ktls_client(stream).await.unwrap();
+
+    (server.await.unwrap(), client)
+}
+
+/// Sends a record of any type through an offloaded socket, the way
+/// `ktls::ffi` sends alerts
+fn send_record(sock: &impl AsRawFd, record_type: u8, payload: &[u8]) {
+    const SOL_TLS: libc::c_int = 282;
+    const TLS_SET_RECORD_TYPE: libc::c_int = 1;
+
+    unsafe {
+        let mut cmsg_buf = [0u8; 64];
+        let mut iov = libc::iovec {
+            iov_base: payload.as_ptr() as *mut libc::c_void,
+            iov_len: payload.len(),
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(1) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = SOL_TLS;
+        (*cmsg).cmsg_type = TLS_SET_RECORD_TYPE;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(1) as _;
+        *libc::CMSG_DATA(cmsg) = record_type;
+
+        let n = libc::sendmsg(sock.as_raw_fd(), &msg, 0);
+        assert_eq!(n, payload.len() as isize, "{}", io::Error::last_os_error());
+    }
+}
+
+/// A SOCKS5 proxy for a single connection, which asks for username/password
+/// authentication, answers it with `auth_reply` and tunnels to `target`
+/// whatever host was requested
+async fn socks5_stub(target: std::net::SocketAddr, auth_reply: [u8; 2]) -> std::net::SocketAddr {
+    let ln = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut client, _) = ln.accept().await.unwrap();
+
+        let mut greeting = [0u8; 2];
+        client.read_exact(&mut greeting).await.unwrap();
+        let mut methods = vec![0u8; greeting[1] as usize];
+        client.read_exact(&mut methods).await.unwrap();
+        assert!(methods.contains(&2));
+        client.write_all(&[5, 2]).await.unwrap();
+
+        let mut auth = [0u8; 2];
+        client.read_exact(&mut auth).await.unwrap();
+        let mut username = vec![0u8; auth[1] as usize];
+        client.read_exact(&mut username).await.unwrap();
+        let mut password = vec![0u8; client.read_u8().await.unwrap() as usize];
+        client.read_exact(&mut password).await.unwrap();
+        client.write_all(&auth_reply).await.unwrap();
+        if auth_reply != [1, 0] {
+            return;
+        }
+
+        let mut req = [0u8; 4];
+        client.read_exact(&mut req).await.unwrap();
+        assert_eq!(req[..3], [5, 1, 0]);
+        assert_eq!(req[3], 3, "the host should be sent as a domain name");
+        let mut host = vec![0u8; client.read_u8().await.unwrap() as usize + 2];
+        client.read_exact(&mut host).await.unwrap();
+
+        let mut upstream = TcpStream::connect(target).await.unwrap();
+        client
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+    });
+
+    addr
+}
+
 struct SpyStream<IO>(IO, &'static str);
 
 impl<IO> AsyncRead for SpyStream<IO>
@@ -561,11 +1676,54 @@ where
     }
 }
 
-impl<IO> AsRawFd for SpyStream<IO>
+impl<IO> AsFd for SpyStream<IO>
 where
-    IO: AsRawFd,
+    IO: AsFd,
 {
-    fn as_raw_fd(&self) -> RawFd {
-        self.0.as_raw_fd()
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+/// Never takes a write, and tells when it's dropped
+struct StalledWriter {
+    sock: std::net::UdpSocket,
+    dropped: Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl Drop for StalledWriter {
+    fn drop(&mut self) {
+        self.dropped
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+impl std::os::fd::AsFd for StalledWriter {
+    fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        self.sock.as_fd()
+    }
+}
+
+impl AsyncWrite for StalledWriter {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut task::Context<'_>,
+        _buf: &[u8],
+    ) -> task::Poll<std::io::Result<usize>> {
+        task::Poll::Pending
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut task::Context<'_>,
+    ) -> task::Poll<std::io::Result<()>> {
+        task::Poll::Pending
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut task::Context<'_>,
+    ) -> task::Poll<std::io::Result<()>> {
+        task::Poll::Pending
     }
 }
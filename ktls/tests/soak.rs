@@ -0,0 +1,295 @@
+//! Long-running soak test: keeps many offloaded connections alive with
+//! periodic traffic and reconnects, to catch sequence number drift, fd leaks
+//! and slow memory growth the integration tests are too short to see. Some
+//! connections end with the client rekeying, which the server must fail
+//! cleanly on (offloaded connections can't follow a KeyUpdate). Open fds and
+//! RSS are sampled throughout the run and must stay bounded.
+//!
+//! ```text
+//! KTLS_SOAK_SECS=14400 KTLS_SOAK_CONNS=256 KTLS_SOAK_MAX_RSS_GROWTH_KIB=65536 \
+//!     cargo test --release --features soak --test soak -- --nocapture
+//! ```
+#![cfg(feature = "soak")]
+
+use std::{
+    io,
+    os::fd::AsRawFd,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use ktls::CorkStream;
+use rcgen::generate_simple_self_signed;
+use rustls::{client::Resumption, ClientConfig, RootCertStore, ServerConfig};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Exchanges per connection before reconnecting
+const ROUNDS_PER_CONNECTION: usize = 1000;
+/// Payloads stay under the socket buffer sizes, since the client only reads
+/// the echo back once it wrote everything
+const MAX_PAYLOAD: usize = 16 * 1024;
+const PAUSE: Duration = Duration::from_millis(10);
+/// One connection in this many ends with a KeyUpdate rather than a shutdown
+const REKEY_EVERY: u64 = 4;
+/// How often open fds and RSS are sampled
+const SAMPLE_EVERY: Duration = Duration::from_secs(1);
+
+#[tokio::test(flavor = "multi_thread")]
+async fn soak() {
+    let duration = Duration::from_secs(env_or("KTLS_SOAK_SECS", 600));
+    let conns = env_or("KTLS_SOAK_CONNS", 64);
+
+    let (acceptor, connector) = tls_configs();
+    let ln = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = ln.local_addr().unwrap();
+
+    let fds_before = open_fds();
+    let rss_before = rss_kib();
+    // a client and a server socket per connection, plus whatever the runtime
+    // opens along the way
+    let max_fds = fds_before + 2 * conns as usize + 64;
+    let max_rss = rss_before + env_or("KTLS_SOAK_MAX_RSS_GROWTH_KIB", 64 * 1024);
+    let rekeys = Arc::new(AtomicUsize::new(0));
+
+    let server = tokio::spawn({
+        let rekeys = rekeys.clone();
+        async move {
+            loop {
+                let (tcp, _) = ln.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                let rekeys = rekeys.clone();
+                tokio::spawn(async move {
+                    let tls = acceptor.accept(CorkStream::new(tcp)).await.unwrap();
+                    let mut stream = ktls::config_ktls_server(tls).await.unwrap();
+                    let mut buf = vec![0u8; MAX_PAYLOAD];
+                    loop {
+                        let n = match stream.read(&mut buf).await {
+                            Ok(0) => break,
+                            Ok(n) => n,
+                            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                                rekeys.fetch_add(1, Ordering::Relaxed);
+                                break;
+                            }
+                            Err(e) => panic!("read failed: {e}"),
+                        };
+                        stream.write_all(&buf[..n]).await.unwrap();
+                    }
+                });
+            }
+        }
+    });
+
+    let peak_fds = Arc::new(AtomicUsize::new(fds_before));
+    let peak_rss = Arc::new(AtomicU64::new(rss_before));
+    let sampler = tokio::spawn({
+        let (peak_fds, peak_rss) = (peak_fds.clone(), peak_rss.clone());
+        async move {
+            loop {
+                tokio::time::sleep(SAMPLE_EVERY).await;
+                peak_fds.fetch_max(open_fds(), Ordering::Relaxed);
+                peak_rss.fetch_max(rss_kib(), Ordering::Relaxed);
+            }
+        }
+    });
+
+    let deadline = Instant::now() + duration;
+    let clients = (0..conns)
+        .map(|id| {
+            tokio::spawn(client_loop(
+                id,
+                addr.to_string(),
+                connector.clone(),
+                deadline,
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    let (mut total, mut rekeys_sent) = (0, 0);
+    for client in clients {
+        let (echoed, rekeyed) = client.await.unwrap();
+        total += echoed;
+        rekeys_sent += rekeyed;
+    }
+    server.abort();
+    sampler.abort();
+
+    // give the server tasks a moment to notice the connections are gone
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let fds_after = open_fds();
+    let rss_after = rss_kib();
+    let peak_fds = peak_fds.load(Ordering::Relaxed);
+    let peak_rss = peak_rss.load(Ordering::Relaxed);
+    let rekeys = rekeys.load(Ordering::Relaxed);
+    println!("soak: {total} bytes echoed over {conns} connections in {duration:?}");
+    println!("soak: {rekeys}/{rekeys_sent} connections ended with a KeyUpdate");
+    println!("soak: fds {fds_before} -> {fds_after} (peak {peak_fds})");
+    println!("soak: rss {rss_before} KiB -> {rss_after} KiB (peak {peak_rss} KiB)");
+
+    // every client that rekeyed waited for the server to drop the connection
+    assert_eq!(rekeys, rekeys_sent, "KeyUpdates the server didn't fail on");
+    // the listener is gone, so there should be no more fds than before
+    assert!(fds_after <= fds_before, "leaked file descriptors");
+    assert!(peak_fds <= max_fds, "more fds open than connections");
+    assert!(
+        peak_rss <= max_rss,
+        "RSS grew past KTLS_SOAK_MAX_RSS_GROWTH_KIB"
+    );
+    assert!(
+        rss_after <= max_rss,
+        "RSS grew past KTLS_SOAK_MAX_RSS_GROWTH_KIB"
+    );
+}
+
+/// Returns the number of bytes echoed and of connections that ended with a
+/// KeyUpdate
+async fn client_loop(
+    id: u64,
+    addr: String,
+    connector: TlsConnector,
+    deadline: Instant,
+) -> (u64, usize) {
+    let mut rng = XorShift(id + 1);
+    let mut echoed = 0;
+    let mut rekeyed = 0;
+    let mut connection = 0u64;
+
+    while Instant::now() < deadline {
+        connection += 1;
+        let tcp = TcpStream::connect(&addr).await.unwrap();
+        let tls = connector
+            .connect("localhost".try_into().unwrap(), CorkStream::new(tcp))
+            .await
+            .unwrap();
+        let mut stream = ktls::config_ktls_client(tls).await.unwrap();
+
+        for _ in 0..ROUNDS_PER_CONNECTION {
+            if Instant::now() >= deadline {
+                break;
+            }
+
+            let len = 1 + (rng.next() as usize % MAX_PAYLOAD);
+            let payload = (0..len).map(|_| rng.next() as u8).collect::<Vec<_>>();
+            stream.write_all(&payload).await.unwrap();
+
+            let mut buf = vec![0u8; len];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(
+                buf, payload,
+                "conn {id}: corrupted echo after {echoed} bytes"
+            );
+
+            echoed += len as u64;
+            tokio::time::sleep(PAUSE).await;
+        }
+
+        if connection.is_multiple_of(REKEY_EVERY) {
+            // KeyUpdate, update_not_requested: the server's reads fail from
+            // there, it drops the connection
+            send_record(&stream, 22, &[24, 0, 0, 1, 0]);
+            let mut buf = [0u8; 1];
+            assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+            rekeyed += 1;
+        } else {
+            stream.shutdown().await.unwrap();
+        }
+    }
+
+    (echoed, rekeyed)
+}
+
+/// Sends a record of any type through an offloaded socket
+fn send_record(sock: &impl AsRawFd, record_type: u8, payload: &[u8]) {
+    const SOL_TLS: libc::c_int = 282;
+    const TLS_SET_RECORD_TYPE: libc::c_int = 1;
+
+    unsafe {
+        let mut cmsg_buf = [0u8; 64];
+        let mut iov = libc::iovec {
+            iov_base: payload.as_ptr() as *mut libc::c_void,
+            iov_len: payload.len(),
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(1) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = SOL_TLS;
+        (*cmsg).cmsg_type = TLS_SET_RECORD_TYPE;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(1) as _;
+        *libc::CMSG_DATA(cmsg) = record_type;
+
+        let n = libc::sendmsg(sock.as_raw_fd(), &msg, 0);
+        assert_eq!(n, payload.len() as isize, "{}", io::Error::last_os_error());
+    }
+}
+
+fn tls_configs() -> (TlsAcceptor, TlsConnector) {
+    let cert = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+    let mut server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(cert.serialize_der().unwrap())],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )
+        .unwrap();
+    server_config.enable_secret_extraction = true;
+
+    let mut root_certs = RootCertStore::empty();
+    root_certs
+        .add(&rustls::Certificate(cert.serialize_der().unwrap()))
+        .unwrap();
+    let mut client_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_certs)
+        .with_no_client_auth();
+    client_config.enable_secret_extraction = true;
+    client_config.resumption = Resumption::disabled();
+
+    (
+        TlsAcceptor::from(Arc::new(server_config)),
+        TlsConnector::from(Arc::new(client_config)),
+    )
+}
+
+fn env_or(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn open_fds() -> usize {
+    std::fs::read_dir("/proc/self/fd").unwrap().count()
+}
+
+fn rss_kib() -> u64 {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmRSS:"))
+        .and_then(|v| v.trim().trim_end_matches("kB").trim().parse().ok())
+        .unwrap_or(0)
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
@@ -293,30 +293,30 @@ async fn handle_client(
     let Some(tls_acceptor) = &upstream.tls_acceptor else {
         // If we're not doing TLS, just copy the data
         let splicer = SpliceSyscall {};
-        let ret = tokio::time::timeout(
+        copy_with_timeout(
             upstream.cfg.cnx_max_duration,
             splicer.splice_bidirectional(&mut stream, &mut sock),
         )
         .await;
-        match ret {
-            Ok(Ok(_)) => {}
-            Ok(Err(err)) => {
-                warn!("closing cnx {:?}", err);
-            }
-            Err(_) => {
-                warn!(
-                    "timeout of {:?} elapsed. Closing cnx",
-                    upstream.cfg.cnx_max_duration
-                );
-            }
-        }
         return Ok(());
     };
 
     // TLS TERMINATION
-    // Setup KTLS
     let tls_stream = tls_acceptor.accept(ktls::CorkStream::new(stream)).await?;
-    let ktls_stream = ktls::config_ktls_server(tls_stream).await?;
+
+    // Setup KTLS, or stay in userspace if it's disabled or unavailable
+    let ktls_stream =
+        match ktls::config_ktls_server_or_fallback(tls_stream, &ktls::KtlsConfig::new()).await? {
+            ktls::MaybeKtlsStream::Ktls(ktls_stream) => ktls_stream,
+            ktls::MaybeKtlsStream::Rustls(mut tls_stream) => {
+                copy_with_timeout(
+                    upstream.cfg.cnx_max_duration,
+                    tokio::io::copy_bidirectional(&mut tls_stream, &mut sock),
+                )
+                .await;
+                return Ok(());
+            }
+        };
     let (drain, tcp) = ktls_stream.into_raw();
     if let Some(data) = drain {
         sock.write_all(&data).await?;
@@ -354,6 +354,22 @@ async fn handle_client(
     Ok(())
 }
 
+/// Runs `copy` for at most `max_duration`, logging why the connection ended
+async fn copy_with_timeout<T>(
+    max_duration: Duration,
+    copy: impl std::future::Future<Output = std::io::Result<T>>,
+) {
+    match tokio::time::timeout(max_duration, copy).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(err)) => {
+            warn!("closing cnx {:?}", err);
+        }
+        Err(_) => {
+            warn!("timeout of {:?} elapsed. Closing cnx", max_duration);
+        }
+    }
+}
+
 async fn extract_tls_info(
     stream: &TcpStream,
 ) -> anyhow::Result<(Option<DnsName>, Option<Vec<ProtocolName>>)> {